@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_docdb::docdb;
+use rust_docdb::query;
+use serde_json::json;
+use sled::Db;
+use tempfile::TempDir;
+
+const N_DOCS: i64 = 20_000;
+
+// Each field has a different number of distinct values, so the
+// queries combine predicates of differing selectivity.
+fn setup() -> (TempDir, Db) {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..N_DOCS {
+        let doc = json!({
+            "n": i,
+            "flag": i % 2 == 0,
+            "group": i % 10,
+            "bucket": i % 100,
+            "score": i % 1000,
+        });
+        docdb::set_document(&db, &format!("doc{:05}", i), doc).unwrap();
+    }
+    (tmp_dir, db)
+}
+
+fn bench_and(c: &mut Criterion) {
+    let (_tmp_dir, db) = setup();
+    let queries = [
+        ("eq_eq", "group = 3 AND bucket = 13"),
+        ("eq_eq_unselective", "flag = true AND group = 4"),
+        ("eq_eq_eq", "flag = true AND group = 4 AND score = 14"),
+        ("eq_range", "bucket = 13 AND score >= 500"),
+        ("range_range", "score >= 100 AND n < 5000"),
+    ];
+    for (name, q) in queries {
+        c.bench_function(name, |b| {
+            b.iter(|| query::search_index(&db, query::parse(q).unwrap()).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, bench_and);
+criterion_main!(benches);
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use sled::Db;
+
+use crate::{
+    docdb::DocDbError,
+    encoding,
+    pathvalues::path_matches,
+    query::{self, JsonType, Query, QueryStats, TaggableValue},
+};
+
+// Aggregations and distinct values are computed from the index keys,
+// which hold each value at a path alongside the document ID, rather
+// than by fetching and decoding documents.
+
+// Agg is an aggregation function. Apart from Count, which counts the
+// documents in a group, they are over the numbers at a path; other
+// values are ignored. For AnyIndex paths, each element is a value, so
+// equal elements of a document are each counted.
+pub enum Agg {
+    Count,
+    Sum(Vec<TaggableValue>),
+    Avg(Vec<TaggableValue>),
+    Min(Vec<TaggableValue>),
+    Max(Vec<TaggableValue>),
+}
+
+pub struct AggregateResult {
+    pub groups: Vec<Group>,
+    pub stats: QueryStats,
+}
+
+pub struct Group {
+    // The value at the group by path. Documents without a value are
+    // grouped under None, after the other groups, which are in index
+    // order. Without a group by path, there's one group, of None.
+    pub key: Option<TaggableValue>,
+    // The result of each Agg, in order. Avg, Min and Max are None when
+    // the group has no numbers at their path.
+    pub values: Vec<Option<f64>>,
+}
+
+pub struct DistinctResult {
+    // Each value at the path, in index order, with the number of
+    // documents that have it.
+    pub values: Vec<(TaggableValue, usize)>,
+    pub stats: QueryStats,
+}
+
+// The running totals for one Agg in one group.
+#[derive(Default)]
+struct Acc {
+    n: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Acc {
+    fn add(&mut self, v: f64) {
+        self.n += 1;
+        self.sum += v;
+        self.min = Some(self.min.map_or(v, |m| m.min(v)));
+        self.max = Some(self.max.map_or(v, |m| m.max(v)));
+    }
+}
+
+// Aggregates the documents matching q, or every document if q is empty,
+// into groups by their value at group_by. For AnyIndex group by paths,
+// a document is in the group of each of its elements' values.
+pub fn aggregate(
+    db: &Db,
+    q: Query,
+    group_by: Option<&Vec<TaggableValue>>,
+    aggs: &[Agg],
+) -> Result<AggregateResult, DocDbError> {
+    if let Some(p) = group_by {
+        query::check_path(p)?;
+    }
+    let (ids, mut stats) = filter_ids(db, q)?;
+
+    // Groups are keyed by their encoded value, so they're in index
+    // order. Documents without a group value are in the group keyed
+    // by an empty Vec, which no encoded value is.
+    let mut doc_groups: BTreeMap<String, Vec<Vec<u8>>> = BTreeMap::new();
+    let mut keys: BTreeMap<Vec<u8>, TaggableValue> = BTreeMap::new();
+    if let Some(p) = group_by {
+        let types = (JsonType::Null, JsonType::String);
+        scan_values(db, p, types, &mut stats, |v, id| {
+            if ids.contains(&id) {
+                let k = encoding::encode_value(&v);
+                let groups = doc_groups.entry(id).or_default();
+                if !groups.contains(&k) {
+                    groups.push(k.clone());
+                }
+                keys.entry(k).or_insert(v);
+            }
+        })?;
+    }
+    let no_group = vec![vec![]];
+    let groups_of = |id: &String| doc_groups.get(id).unwrap_or(&no_group);
+
+    // encoded group value => (document count, an Acc for each Agg)
+    let mut groups: BTreeMap<Vec<u8>, (usize, Vec<Acc>)> = BTreeMap::new();
+    let new_group = || (0, aggs.iter().map(|_| Acc::default()).collect());
+    if group_by.is_none() {
+        groups.insert(vec![], new_group());
+    }
+    for id in &ids {
+        for k in groups_of(id) {
+            groups.entry(k.clone()).or_insert_with(new_group).0 += 1;
+        }
+    }
+
+    // Scan each path once, for all the Aggs over it
+    let mut paths: BTreeMap<Vec<u8>, (&Vec<TaggableValue>, Vec<usize>)> = BTreeMap::new();
+    for (i, agg) in aggs.iter().enumerate() {
+        match agg {
+            Agg::Count => {}
+            Agg::Sum(p) | Agg::Avg(p) | Agg::Min(p) | Agg::Max(p) => {
+                let entry = paths.entry(encoding::encode_path(p));
+                entry.or_insert_with(|| (p, vec![])).1.push(i);
+            }
+        }
+    }
+    for (p, aggs) in paths.values() {
+        let add = |v, id: String| {
+            let v = match v {
+                TaggableValue::Number(v) if ids.contains(&id) => v,
+                _ => return,
+            };
+            for k in groups_of(&id) {
+                if let Some((_, accs)) = groups.get_mut(k) {
+                    for i in aggs {
+                        accs[*i].add(v);
+                    }
+                }
+            }
+        };
+        if p.contains(&TaggableValue::AnyIndex) {
+            scan_elements(db, p, &mut stats, add)?;
+        } else {
+            let types = (JsonType::Number, JsonType::Number);
+            scan_values(db, p, types, &mut stats, add)?;
+        }
+    }
+
+    let results = |accs: Vec<Acc>, count: usize| -> Vec<Option<f64>> {
+        aggs.iter()
+            .zip(accs)
+            .map(|(agg, acc)| match agg {
+                Agg::Count => Some(count as f64),
+                Agg::Sum(_) => Some(acc.sum),
+                Agg::Avg(_) => (acc.n > 0).then(|| acc.sum / acc.n as f64),
+                Agg::Min(_) => acc.min,
+                Agg::Max(_) => acc.max,
+            })
+            .collect()
+    };
+    let ungrouped = groups.remove(&vec![]);
+    let mut result = vec![];
+    for (k, (count, accs)) in groups {
+        result.push(Group {
+            key: keys.remove(&k),
+            values: results(accs, count),
+        });
+    }
+    if let Some((count, accs)) = ungrouped {
+        result.push(Group {
+            key: None,
+            values: results(accs, count),
+        });
+    }
+    Ok(AggregateResult {
+        groups: result,
+        stats,
+    })
+}
+
+// Returns the distinct values at path p with the number of documents
+// that have each, counting only those matching q if it's given.
+pub fn distinct(
+    db: &Db,
+    p: &Vec<TaggableValue>,
+    q: Option<Query>,
+) -> Result<DistinctResult, DocDbError> {
+    query::check_path(p)?;
+    let (ids, mut stats) = match q {
+        Some(q) => {
+            let (ids, stats) = filter_ids(db, q)?;
+            (Some(ids), stats)
+        }
+        None => (None, QueryStats::default()),
+    };
+    // Keys for the same value are together, so we only need to
+    // count the current value's documents.
+    let mut values: Vec<(TaggableValue, usize)> = vec![];
+    let types = (JsonType::Null, JsonType::String);
+    scan_values(db, p, types, &mut stats, |v, id| {
+        if ids.as_ref().is_some_and(|ids| !ids.contains(&id)) {
+            return;
+        }
+        match values.last_mut() {
+            Some((last, n)) if *last == v => *n += 1,
+            _ => values.push((v, 1)),
+        }
+    })?;
+    Ok(DistinctResult { values, stats })
+}
+
+// Returns the IDs of the documents matching q, or every document
+// if q is empty.
+fn filter_ids(db: &Db, q: Query) -> Result<(BTreeSet<String>, QueryStats), DocDbError> {
+    if q.is_empty() {
+        let mut stats = QueryStats::default();
+        return Ok((query::all_docids(db, &mut stats)?, stats));
+    }
+    let r = query::search_index(db, q)?;
+    Ok((r.results.into_iter().collect(), r.stats))
+}
+
+// Scans the index keys for values at exactly path p with JSON types
+// from types.0 to types.1, calling f with each value and doc ID.
+fn scan_values(
+    db: &Db,
+    p: &Vec<TaggableValue>,
+    types: (JsonType, JsonType),
+    stats: &mut QueryStats,
+    mut f: impl FnMut(TaggableValue, String),
+) -> Result<(), DocDbError> {
+    let field = encoding::encode_path(p);
+    let start_key = encoding::query_type_lower_bound(p, types.0);
+    let end_key = encoding::query_type_upper_bound(p, types.1);
+    let scan = stats.start_scan();
+    for i in db.range(start_key..end_key) {
+        let (k, v) = i?;
+        stats.read_key(scan, &k, &v);
+        match encoding::decode_index_key(&k) {
+            // Keys for fields below p can be in the range too
+            Ok((path, v, docid)) if encoding::encode_path(&path) == field => f(v, docid),
+            Ok(_) => {}
+            Err(_) => stats.decode_failures += 1,
+        }
+    }
+    Ok(())
+}
+
+// Scans the index keys for the value of every element matching AnyIndex
+// path p, calling f with each value and doc ID. The keys of p itself
+// only have each distinct value of a document once, so instead this
+// reads the keys of the elements' paths, which are below the part of p
+// before its first AnyIndex.
+fn scan_elements(
+    db: &Db,
+    p: &[TaggableValue],
+    stats: &mut QueryStats,
+    mut f: impl FnMut(TaggableValue, String),
+) -> Result<(), DocDbError> {
+    let i = p.iter().position(|c| *c == TaggableValue::AnyIndex);
+    let parent = p[..i.unwrap_or(p.len())].to_vec();
+    let start_key = encoding::query_lower_bound(&parent, None);
+    let end_key = encoding::query_upper_bound(&parent, None);
+    let scan = stats.start_scan();
+    for i in db.range(start_key..end_key) {
+        let (k, v) = i?;
+        stats.read_key(scan, &k, &v);
+        match encoding::decode_index_key(&k) {
+            Ok((path, v, docid)) if path_matches(p, &path) => f(v, docid),
+            Ok(_) => {}
+            Err(_) => stats.decode_failures += 1,
+        }
+    }
+    Ok(())
+}
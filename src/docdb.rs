@@ -1,12 +1,31 @@
+use std::collections::BTreeSet;
+
 use serde_json::Value;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
 use sled::Db;
 
-use crate::encoding::{encode_document_key, encode_index_key};
-use crate::pathvalues::get_path_values;
+use crate::encoding::{
+    self, encode_array_size_key, encode_document_key, encode_document_ordinal_key,
+    encode_index_key, encode_ordinal_key,
+};
+use crate::pathvalues::{get_array_sizes, get_indexed_path_values};
+use crate::query::{Limit, QueryStats};
+use crate::stats::{self, Deltas};
+use crate::text::{self, TextIndex};
 
 #[derive(Debug)]
 pub enum DocDbError {
     GenericError,
+    // An InvalidQuery error means the query can't be evaluated,
+    // eg, a predicate was used where it isn't supported.
+    InvalidQuery(String),
+    // A LimitExceeded error means a query stopped at one of the limits
+    // in its QueryOptions, or was cancelled. The stats are of the work
+    // it did before stopping.
+    LimitExceeded(Limit, QueryStats),
     DocDecode(rmp_serde::decode::Error),
     DocEncode(rmp_serde::encode::Error),
     // A Db error indicates the underlying file
@@ -33,6 +52,24 @@ impl From<rmp_serde::decode::Error> for DocDbError {
     }
 }
 
+impl From<TransactionError<DocDbError>> for DocDbError {
+    fn from(value: TransactionError<DocDbError>) -> Self {
+        match value {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => DocDbError::Db(e),
+        }
+    }
+}
+
+// The result of part of a write's transaction. sled's errors are kept
+// as they are, so that the transaction is retried if it conflicts
+// with another, and ours abort it.
+pub(crate) type TxResult<T> = ConflictableTransactionResult<T, DocDbError>;
+
+fn abort<E: Into<DocDbError>>(e: E) -> ConflictableTransactionError<DocDbError> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
 // Retrieve a document from db by key.
 pub fn get_document(db: &Db, docid: &str) -> Result<Option<serde_json::Value>, DocDbError> {
     let readvalue = db.get(encode_document_key(docid))?;
@@ -44,65 +81,256 @@ pub fn get_document(db: &Db, docid: &str) -> Result<Option<serde_json::Value>, D
     Ok(Some(doc))
 }
 
-// Insert and index v into db at key
+// WriteOptions control the optional indexing done when a
+// document is written.
+#[derive(Default)]
+pub struct WriteOptions {
+    // When set, the listed fields are added to the full-text index,
+    // replacing the document's entries from earlier writes. When not
+    // set, an updated document keeps those entries as they were, as
+    // analyzers can't be stored to reapply them, so pass the TextIndex
+    // again to index changed text. A TextIndex with no paths removes
+    // the document from the full-text index.
+    pub text: Option<TextIndex>,
+}
+
+// Insert and index v into db at key. If the document was added to the
+// full-text index when it was last written, its entries are kept; see
+// WriteOptions.text.
 pub fn set_document(db: &Db, docid: &str, v: serde_json::Value) -> Result<(), DocDbError> {
-    let mut batch = sled::Batch::default();
-    match get_document(&db, &docid)? {
-        Some(v) => delete_batch(&mut batch, docid, v),
-        None => {}
-    };
-    insert_batch(&mut batch, docid, v)?;
-    db.apply_batch(batch).map_err(|e| DocDbError::Db(e))
+    set_document_with_options(db, docid, v, &WriteOptions::default())
 }
 
-// Adds commands to add and index `v` to the database to a batch
+// Insert and index v into db at key, using opts to control indexing.
+// The document and its index keys are written in one transaction,
+// which sled retries if a concurrent write to the same document
+// conflicts. The query planning counts are updated after it commits;
+// see stats.rs.
+pub fn set_document_with_options(
+    db: &Db,
+    docid: &str,
+    v: serde_json::Value,
+    opts: &WriteOptions,
+) -> Result<(), DocDbError> {
+    let deltas = db.transaction(|tx| {
+        let mut batch = sled::Batch::default();
+        let mut deltas = Deltas::default();
+        delete_batch(tx, &mut batch, &mut deltas, docid, opts.text.is_some())?;
+        // An updated document keeps its ordinal
+        let existing = tx.get(encode_document_ordinal_key(docid))?;
+        let ordinal = match existing.and_then(|o| encoding::decode_ordinal(&o).ok()) {
+            Some(ordinal) => ordinal,
+            None => tx.generate_id()?,
+        };
+        insert_batch(&mut batch, &mut deltas, docid, ordinal, v.clone(), opts).map_err(abort)?;
+        tx.apply_batch(&batch)?;
+        Ok(deltas)
+    })?;
+    stats::apply(db, deltas)
+}
+
+// Adds commands to add and index `v` to the database to a batch.
+// Each document has a numeric ordinal, which is the value of its
+// index keys, so queries can collect the matching documents in
+// compact bitmaps rather than sets of doc IDs.
 fn insert_batch(
     batch: &mut sled::Batch,
+    deltas: &mut Deltas,
     docid: &str,
+    ordinal: u64,
     v: serde_json::Value,
+    opts: &WriteOptions,
 ) -> Result<(), DocDbError> {
+    if let Some(t) = &opts.text {
+        text::insert_batch(batch, docid, &v, t)?;
+    }
+
     // pack the json into msgpack for storage
     let buf = rmp_serde::to_vec(&v)?;
     batch.insert(encode_document_key(docid), buf);
 
-    // v is moved into get_path_values. This might not be possible
+    batch.insert(encode_ordinal_key(ordinal), docid);
+    batch.insert(
+        encode_document_ordinal_key(docid),
+        &encoding::encode_ordinal(ordinal),
+    );
+
+    let ordinal = encoding::encode_ordinal(ordinal);
+    // Record array lengths separately, as the path values only
+    // contain the leaves of the document.
+    for (path, n) in get_array_sizes(&v) {
+        batch.insert(encode_array_size_key(docid, &path, n), &ordinal);
+    }
+
+    // v is moved into get_indexed_path_values. This might not be possible
     // if we later needed v, but we don't yet.
-    let path_values = get_path_values(v);
+    let path_values = get_indexed_path_values(v);
 
-    let sentinal_value: [u8; 0] = [];
     // Here we would be indexing the path_values, so we can
-    // consume them as we don't need them afterwards
+    // consume them as we don't need them afterwards. An array with
+    // a value more than once has one index key for it under AnyIndex,
+    // so it's only counted once.
+    let mut keys = BTreeSet::new();
     for (path, v) in path_values {
         let k = encode_index_key(docid, &path, &v);
-        batch.insert(k, &sentinal_value);
+        if keys.insert(k.clone()) {
+            deltas.add(&path, &v, 1);
+        }
+        batch.insert(k, &ordinal);
     }
 
     Ok(())
 }
 
 pub fn delete_document(db: &Db, docid: &str) -> Result<(), DocDbError> {
-    // If the document isn't in the database, assume it's okay
-    let mut batch = sled::Batch::default();
-    match get_document(&db, &docid)? {
-        Some(v) => delete_batch(&mut batch, docid, v),
-        None => {}
-    };
-    db.apply_batch(batch).map_err(|e| DocDbError::Db(e))
+    let deltas = db.transaction(|tx| {
+        let mut batch = sled::Batch::default();
+        let mut deltas = Deltas::default();
+        delete_batch(tx, &mut batch, &mut deltas, docid, true)?;
+        tx.apply_batch(&batch)?;
+        Ok(deltas)
+    })?;
+    stats::apply(db, deltas)
 }
 
-// Adds commands to remove v from the database to a batch
-fn delete_batch(batch: &mut sled::Batch, docid: &str, v: serde_json::Value) {
-    let path_values = get_path_values(v);
+// Adds commands to remove docid's document from the database to a
+// batch, and its full-text index entries if text is set. If the
+// document isn't in the database, assume it's okay.
+fn delete_batch(
+    tx: &TransactionalTree,
+    batch: &mut sled::Batch,
+    deltas: &mut Deltas,
+    docid: &str,
+    text: bool,
+) -> TxResult<()> {
+    let v = match tx.get(encode_document_key(docid))? {
+        Some(packed) => rmp_serde::from_slice::<Value>(&packed).map_err(abort)?,
+        None => return Ok(()),
+    };
+    if text {
+        let text_keys = tx.get(encoding::encode_text_document_key(docid))?;
+        text::delete_batch(batch, docid, text_keys.as_deref()).map_err(abort)?;
+    }
+    let k = encode_document_ordinal_key(docid);
+    if let Some(ordinal) = tx.get(&k)? {
+        if let Ok(ordinal) = encoding::decode_ordinal(&ordinal) {
+            batch.remove(encode_ordinal_key(ordinal));
+        }
+        batch.remove(k);
+    }
+    for (path, n) in get_array_sizes(&v) {
+        batch.remove(encode_array_size_key(docid, &path, n));
+    }
+    let path_values = get_indexed_path_values(v);
+    let mut keys = BTreeSet::new();
     for (path, v) in path_values {
         let k = encode_index_key(docid, &path, &v);
+        if keys.insert(k.clone()) {
+            deltas.add(&path, &v, -1);
+        }
         batch.remove(k);
     }
     batch.remove(encode_document_key(docid));
+    Ok(())
 }
 
-pub fn new_database(path: &std::path::Path) -> sled::Result<Db> {
+// Rebuilds the index, array size keys, ordinals and query planning
+// counts from the stored documents, which also corrects counts that
+// have drifted from the index. The full-text index is left as it
+// is. new_database runs it for databases from before the format key,
+// which have neither ordinals nor counts. It isn't atomic, so shouldn't
+// be run while documents are being written, and queries during it can
+// miss documents. If it's interrupted, run it again.
+pub fn reindex(db: &Db) -> Result<(), DocDbError> {
+    for (start_key, end_key) in encoding::derived_key_ranges() {
+        for k in db.range(start_key..end_key).keys() {
+            db.remove(k?)?;
+        }
+    }
+    let start_key = encoding::encode_document_query_start_key();
+    let end_key = encoding::encode_document_query_end_key();
+    for kv in db.range(start_key..end_key) {
+        let (k, packed) = kv?;
+        let docid =
+            encoding::decode_document_key_docid(&k).map_err(|_| DocDbError::GenericError)?;
+        let v = rmp_serde::from_slice::<Value>(&packed)?;
+        let deltas = db.transaction(|tx| {
+            let mut batch = sled::Batch::default();
+            let mut deltas = Deltas::default();
+            let ordinal = tx.generate_id()?;
+            let opts = WriteOptions::default();
+            insert_batch(&mut batch, &mut deltas, docid, ordinal, v.clone(), &opts)
+                .map_err(abort)?;
+            tx.apply_batch(&batch)?;
+            Ok(deltas)
+        })?;
+        stats::apply(db, deltas)?;
+    }
+    Ok(())
+}
+
+pub fn new_database(path: &std::path::Path) -> Result<Db, DocDbError> {
     // return sled::open(path);
     // works like std::fs::open
     let db = sled::open(path)?;
+    upgrade(&db)?;
     Ok(db)
 }
+
+// Brings a database written by an earlier version up to the current
+// format. Those from before the format key have index keys without
+// ordinals, which queries can't read, so they are reindexed.
+fn upgrade(db: &Db) -> Result<(), DocDbError> {
+    let k = encoding::encode_format_key();
+    if db.get(&k)?.is_some() {
+        return Ok(());
+    }
+    let start_key = encoding::encode_document_query_start_key();
+    let end_key = encoding::encode_document_query_end_key();
+    if db.range(start_key..end_key).next().is_some() {
+        reindex(db)?;
+    }
+    db.insert(k, &encoding::FORMAT_VERSION.to_be_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{parser::parse, query::search_index};
+
+    #[test]
+    fn test_upgrade() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = new_database(tmp_dir.path())?;
+        set_document(&db, "doc1", json!({"name": "mike", "age": 40}))?;
+        set_document(&db, "doc2", json!({"name": "john", "age": 24}))?;
+
+        // Make it look like a database from before ordinals
+        db.remove(encoding::encode_format_key())?;
+        for (start_key, end_key) in encoding::derived_key_ranges() {
+            for kv in db.range(start_key..end_key) {
+                let (k, _) = kv?;
+                match k[0] {
+                    encoding::KEY_INDEX | encoding::KEY_ARRAY_SIZE => db.insert(k, vec![])?,
+                    _ => db.remove(k)?,
+                };
+            }
+        }
+        assert!(search_index(&db, parse("age > 30").unwrap())?
+            .results
+            .is_empty());
+
+        // As new_database does when opening it
+        upgrade(&db)?;
+        assert!(db.get(encoding::encode_format_key())?.is_some());
+        let r = search_index(&db, parse("age > 30").unwrap())?;
+        assert_eq!(vec!["doc1".to_string()], r.results);
+        let r = search_index(&db, parse("name = \"john\" AND age < 30").unwrap())?;
+        assert_eq!(vec!["doc2".to_string()], r.results);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use std::error::Error;
 use std::{fmt, str};
 
-use crate::query::TaggableValue;
+use crate::query::{JsonType, TaggableValue};
 
 // cribbed from https://stackoverflow.com/a/75994861 --- this
 // allows us to create a vec of TaggableValues from normal
@@ -18,10 +18,7 @@ use crate::query::TaggableValue;
 macro_rules! keypath {
     ( $( $x:expr ),* ) => {
         {
-            let mut temp_vec = Vec::<TaggableValue>::new();
-            $(
-                temp_vec.push(TaggableValue::from($x));
-            )*
+            let temp_vec: Vec<TaggableValue> = vec![$(TaggableValue::from($x)),*];
             temp_vec
         }
     };
@@ -41,13 +38,69 @@ impl Error for DecodeError {}
 // keys for primary document data from index data. They are
 // prefixed to the encoded keys.
 const KEY_DOCUMENT: u8 = 1u8;
-const KEY_INDEX: u8 = 2u8;
+pub(crate) const KEY_INDEX: u8 = 2u8;
+pub(crate) const KEY_ARRAY_SIZE: u8 = 3u8;
+// The full-text index keys; see text.rs.
+const KEY_TEXT_POSTING: u8 = 4u8;
+const KEY_TEXT_LENGTH: u8 = 5u8;
+const KEY_TEXT_DOCUMENT: u8 = 6u8;
+// The statistics used to plan queries; see stats.rs.
+pub(crate) const KEY_STATS_PATH: u8 = 7u8;
+pub(crate) const KEY_STATS_VALUE: u8 = 8u8;
+// Map between doc IDs and their ordinals, which are the values of
+// the index and array size keys.
+const KEY_ORDINAL: u8 = 9u8;
+const KEY_DOCUMENT_ORDINAL: u8 = 10u8;
+// The version of the layout of the database's keys and values.
+const KEY_FORMAT: u8 = 11u8;
+
+// FORMAT_VERSION is the current version of the layout. Version 1 added
+// ordinals as the values of the index keys, which were empty before.
+// Databases from before then have no format key.
+pub const FORMAT_VERSION: u64 = 1;
+
+pub fn encode_format_key() -> Vec<u8> {
+    vec![KEY_FORMAT]
+}
+
+// The ranges of the keys derived from the documents, apart from the
+// full-text index, which docdb::reindex rebuilds.
+pub fn derived_key_ranges() -> Vec<(Vec<u8>, Vec<u8>)> {
+    [
+        KEY_INDEX,
+        KEY_ARRAY_SIZE,
+        KEY_STATS_PATH,
+        KEY_STATS_VALUE,
+        KEY_ORDINAL,
+        KEY_DOCUMENT_ORDINAL,
+    ]
+    .iter()
+    .map(|prefix| (vec![*prefix], vec![*prefix + 1]))
+    .collect()
+}
 
 pub fn encode_document_key(docid: &str) -> Vec<u8> {
     let mut k: Vec<u8> = vec![KEY_DOCUMENT, 0x00];
     k.extend(&TaggableValue::from(docid).encode());
     k
 }
+// Encode keys that are guaranteed to be the lower and upper
+// bounds of all document keys, for listing every document ID.
+pub fn encode_document_query_start_key() -> Vec<u8> {
+    vec![KEY_DOCUMENT, 0x00]
+}
+pub fn encode_document_query_end_key() -> Vec<u8> {
+    vec![KEY_DOCUMENT, 0x01]
+}
+
+// Decodes the doc ID from document key k
+pub fn decode_document_key_docid(k: &[u8]) -> Result<&str, DecodeError> {
+    match k {
+        [KEY_DOCUMENT, 0x00, tail @ ..] => decode_tagged_str(tail),
+        _ => Err(DecodeError),
+    }
+}
+
 pub fn encode_index_key(docid: &str, path: &Vec<TaggableValue>, v: &TaggableValue) -> Vec<u8> {
     // we will push everything into the key using
     // the tagged form. Paths must be tagged as they
@@ -65,9 +118,127 @@ pub fn encode_index_key(docid: &str, path: &Vec<TaggableValue>, v: &TaggableValu
     k
 }
 
-// Decodes the doc ID from index key k
+// Array size keys record the length of each array in a document. They
+// have the same layout as index keys, with the length as the value.
+pub fn encode_array_size_key(docid: &str, path: &Vec<TaggableValue>, len: usize) -> Vec<u8> {
+    let mut k = encode_index_key(docid, path, &TaggableValue::Number(len as f64));
+    k[0] = KEY_ARRAY_SIZE;
+    k
+}
+
+// Encode keys that are guaranteed to be the lower and upper bounds of
+// the array size keys with path p and, if given, length len.
+pub fn array_size_query_lower_bound(p: &Vec<TaggableValue>, len: Option<usize>) -> Vec<u8> {
+    let mut k = query_lower_bound(p, len.map(|n| TaggableValue::Number(n as f64)).as_ref());
+    k[0] = KEY_ARRAY_SIZE;
+    k
+}
+pub fn array_size_query_upper_bound(p: &Vec<TaggableValue>, len: Option<usize>) -> Vec<u8> {
+    let mut k = query_upper_bound(p, len.map(|n| TaggableValue::Number(n as f64)).as_ref());
+    k[0] = KEY_ARRAY_SIZE;
+    k
+}
+
+// Text posting keys record that a document's text at path contains term.
+// They have the same layout as index keys, with the term as the value.
+pub fn encode_text_posting_key(docid: &str, path: &Vec<TaggableValue>, term: &str) -> Vec<u8> {
+    let mut k = encode_index_key(docid, path, &TaggableValue::from(term));
+    k[0] = KEY_TEXT_POSTING;
+    k
+}
+// Encode keys that are guaranteed to be the lower and upper
+// bounds of the posting keys for term at path.
+pub fn text_posting_query_lower_bound(path: &Vec<TaggableValue>, term: &str) -> Vec<u8> {
+    let mut k = query_lower_bound(path, Some(&TaggableValue::from(term)));
+    k[0] = KEY_TEXT_POSTING;
+    k
+}
+pub fn text_posting_query_upper_bound(path: &Vec<TaggableValue>, term: &str) -> Vec<u8> {
+    let mut k = query_upper_bound(path, Some(&TaggableValue::from(term)));
+    k[0] = KEY_TEXT_POSTING;
+    k
+}
+
+// Text length keys record the number of terms in a document's text at path.
+pub fn encode_text_length_key(docid: &str, path: &Vec<TaggableValue>) -> Vec<u8> {
+    let mut k = query_lower_bound(path, None);
+    k[0] = KEY_TEXT_LENGTH;
+    k.extend(TaggableValue::from(docid).encode());
+    k
+}
+// Encode keys that are guaranteed to be the lower and upper
+// bounds of the text length keys for path.
+pub fn text_length_query_lower_bound(path: &Vec<TaggableValue>) -> Vec<u8> {
+    let mut k = query_lower_bound(path, None);
+    k[0] = KEY_TEXT_LENGTH;
+    k
+}
+pub fn text_length_query_upper_bound(path: &Vec<TaggableValue>) -> Vec<u8> {
+    let mut k = query_upper_bound(path, None);
+    k[0] = KEY_TEXT_LENGTH;
+    k
+}
+
+// Text document keys hold the text index entries written for a
+// document, so they can be removed without re-analysing its text.
+pub fn encode_text_document_key(docid: &str) -> Vec<u8> {
+    let mut k: Vec<u8> = vec![KEY_TEXT_DOCUMENT, 0x00];
+    k.extend(&TaggableValue::from(docid).encode());
+    k
+}
+
+// Ordinal keys hold the doc ID of an ordinal, and document ordinal
+// keys the ordinal of a doc ID. Ordinals are big-endian u64s, so
+// ordinal keys are in ordinal order.
+pub fn encode_ordinal_key(ordinal: u64) -> Vec<u8> {
+    let mut k: Vec<u8> = vec![KEY_ORDINAL, 0x00];
+    k.extend(encode_ordinal(ordinal));
+    k
+}
+pub fn encode_ordinal_query_start_key() -> Vec<u8> {
+    vec![KEY_ORDINAL, 0x00]
+}
+pub fn encode_ordinal_query_end_key() -> Vec<u8> {
+    vec![KEY_ORDINAL, 0x01]
+}
+pub fn decode_ordinal_key(k: &[u8]) -> Result<u64, DecodeError> {
+    match k {
+        [KEY_ORDINAL, 0x00, tail @ ..] => decode_ordinal(tail),
+        _ => Err(DecodeError),
+    }
+}
+pub fn encode_document_ordinal_key(docid: &str) -> Vec<u8> {
+    let mut k: Vec<u8> = vec![KEY_DOCUMENT_ORDINAL, 0x00];
+    k.extend(&TaggableValue::from(docid).encode());
+    k
+}
+
+pub fn encode_ordinal(ordinal: u64) -> [u8; 8] {
+    ordinal.to_be_bytes()
+}
+pub fn decode_ordinal(v: &[u8]) -> Result<u64, DecodeError> {
+    v.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| DecodeError)
+}
+
+// Stats keys hold the number of index keys with a path, and with
+// a path and value.
+pub fn encode_path_stats_key(path: &Vec<TaggableValue>) -> Vec<u8> {
+    let mut k: Vec<u8> = vec![KEY_STATS_PATH, 0x00];
+    k.extend(path.encode());
+    k
+}
+pub fn encode_value_stats_key(path: &Vec<TaggableValue>, v: &TaggableValue) -> Vec<u8> {
+    let mut k = query_lower_bound(path, Some(v));
+    k[0] = KEY_STATS_VALUE;
+    k
+}
+
+// Decodes the doc ID from index key k. This also works for array
+// size and text keys, which also end with the doc ID.
 pub fn decode_index_key_docid(k: &[u8]) -> Result<&str, DecodeError> {
-    let last = k.split(|b| *b == 0x00).last();
+    let last = k.split(|b| *b == 0x00).next_back();
     match last {
         Some(v) => decode_tagged_str(v),
         None => Err(DecodeError),
@@ -84,6 +255,75 @@ fn decode_tagged_str(tv: &[u8]) -> Result<&str, DecodeError> {
     }
 }
 
+// Decodes the path, value and doc ID from index key k. For array
+// size keys, the value is the length of the array.
+pub fn decode_index_key(
+    k: &[u8],
+) -> Result<(Vec<TaggableValue>, TaggableValue, String), DecodeError> {
+    let mut tail = match k {
+        [KEY_INDEX | KEY_ARRAY_SIZE, 0x00, tail @ ..] => tail,
+        _ => return Err(DecodeError),
+    };
+    let mut components = vec![];
+    loop {
+        let (v, rest) = decode_tagged_value(tail)?;
+        components.push(v);
+        match rest.split_first() {
+            None => break,
+            Some((0x00, rest)) => tail = rest,
+            Some(_) => return Err(DecodeError),
+        }
+    }
+    let docid = match components.pop() {
+        Some(TaggableValue::String(docid)) => docid,
+        _ => return Err(DecodeError),
+    };
+    let v = components.pop().ok_or(DecodeError)?;
+    Ok((components, v, docid))
+}
+
+// Decodes the tagged value at the start of tv, returning
+// the value and the remaining bytes.
+fn decode_tagged_value(tv: &[u8]) -> Result<(TaggableValue, &[u8]), DecodeError> {
+    let (tag, tail) = tv.split_first().ok_or(DecodeError)?;
+    match *tag {
+        x if x == JsonTag::Null as u8 => Ok((TaggableValue::Null, tail)),
+        x if x == JsonTag::False as u8 => Ok((TaggableValue::Bool(false), tail)),
+        x if x == JsonTag::True as u8 => Ok((TaggableValue::Bool(true), tail)),
+        x if x == JsonTag::AnyIndex as u8 => Ok((TaggableValue::AnyIndex, tail)),
+        x if x == JsonTag::Number as u8 => {
+            if tail.len() < 8 {
+                return Err(DecodeError);
+            }
+            let (buf, tail) = tail.split_at(8);
+            // Reverse the transformation in encode
+            let mut bits = u64::from_be_bytes(buf.try_into().map_err(|_| DecodeError)?);
+            if bits & 0x8000000000000000 != 0 {
+                bits ^= 0x8000000000000000
+            } else {
+                bits ^= 0xffffffffffffffff
+            }
+            Ok((TaggableValue::Number(f64::from_bits(bits)), tail))
+        }
+        x if x == JsonTag::String as u8 => {
+            let end = tail.iter().position(|b| *b == 0x00).unwrap_or(tail.len());
+            let (s, tail) = tail.split_at(end);
+            let s = str::from_utf8(s).map_err(|_| DecodeError)?;
+            Ok((TaggableValue::String(s.to_string()), tail))
+        }
+        _ => Err(DecodeError),
+    }
+}
+
+// Encode a path or value in the same form as used in index keys,
+// so they can be compared in the same order as the index.
+pub fn encode_path(path: &Vec<TaggableValue>) -> Vec<u8> {
+    path.encode()
+}
+pub fn encode_value(v: &TaggableValue) -> Vec<u8> {
+    v.encode()
+}
+
 // Encode an index key that is guaranteed to be the lower
 // bound of keys with a given path.
 pub fn encode_index_query_p_start_key(path: &Vec<TaggableValue>) -> Vec<u8> {
@@ -128,6 +368,134 @@ pub fn query_upper_bound(p: &Vec<TaggableValue>, v: Option<&TaggableValue>) -> V
     k
 }
 
+// Encode an index key that is guaranteed to be the first key of
+// string values starting with prefix at the given path.
+pub fn encode_index_query_prefix_start_key(path: &Vec<TaggableValue>, prefix: &str) -> Vec<u8> {
+    let mut k = query_lower_bound(path, None);
+    k.extend(TaggableValue::from(prefix).encode());
+    k
+}
+// Encode an index key that is guaranteed to be after all string values
+// starting with prefix at the given path. 0xff never appears in UTF-8,
+// so it sorts after any continuation of the prefix.
+pub fn encode_index_query_prefix_end_key(path: &Vec<TaggableValue>, prefix: &str) -> Vec<u8> {
+    let mut k = encode_index_query_prefix_start_key(path, prefix);
+    k.push(0xff);
+    k
+}
+
+// Returns whether index or array size key k is for a value at exactly
+// path p rather than at a field below p, where p_len is the length of
+// query_lower_bound(p, None), which k starts with. The keys of fields
+// below p can be within the range of a scan for p's values, as the
+// next component of their path is where a value at p would be.
+pub fn is_key_at_path(k: &[u8], p_len: usize) -> bool {
+    let rest = match k.get(p_len..) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    // The value is followed by just the doc ID
+    match decode_tagged_value(rest) {
+        Ok((_, [0x00, docid @ ..])) => !docid.is_empty() && !docid.contains(&0x00),
+        _ => false,
+    }
+}
+
+// Encode index keys that are guaranteed to be the lower and
+// upper bounds of the values of JSON type t with a given path.
+pub fn query_type_lower_bound(p: &Vec<TaggableValue>, t: JsonType) -> Vec<u8> {
+    let mut k = query_lower_bound(p, None);
+    k.push(type_tags(t).0);
+    k
+}
+pub fn query_type_upper_bound(p: &Vec<TaggableValue>, t: JsonType) -> Vec<u8> {
+    let mut k = query_lower_bound(p, None);
+    k.push(type_tags(t).1 + 1);
+    k
+}
+
+// QueryBound is the position of a scan's start or end key among the
+// keys for its path, for showing scans in a readable form.
+#[derive(Debug, PartialEq)]
+pub enum QueryBound {
+    // Before or after every value at the path
+    Start,
+    End,
+    // Before or after the keys for a value
+    Before(TaggableValue),
+    After(TaggableValue),
+    // Before or after every value of a JSON type
+    BeforeType(JsonType),
+    AfterType(JsonType),
+    // After every string starting with a prefix
+    AfterPrefix(String),
+}
+
+// Decodes k, a start or end key of a scan of the index or array size
+// keys for path p, into its position. Returns None if k isn't a key
+// for p or isn't a bound any query creates.
+pub fn decode_query_bound(p: &Vec<TaggableValue>, k: &[u8]) -> Option<QueryBound> {
+    let path = p.encode();
+    let tail = match k {
+        [KEY_INDEX | KEY_ARRAY_SIZE, 0x00, tail @ ..] => tail.strip_prefix(path.as_slice())?,
+        _ => return None,
+    };
+    let tail = match tail {
+        [0x00] => return Some(QueryBound::Start),
+        [0x01] => return Some(QueryBound::End),
+        [0x00, tail @ ..] => tail,
+        _ => return None,
+    };
+    if let [tag] = tail {
+        let types = [
+            JsonType::Null,
+            JsonType::Bool,
+            JsonType::Number,
+            JsonType::String,
+        ];
+        // The end of one type is the start of the next, so
+        // prefer describing the key as the start of a type.
+        let before = types.iter().find(|t| type_tags(**t).0 == *tag);
+        let after = types.iter().find(|t| type_tags(**t).1 + 1 == *tag);
+        return match (before, after) {
+            (Some(t), _) => Some(QueryBound::BeforeType(*t)),
+            (None, Some(t)) => Some(QueryBound::AfterType(*t)),
+            (None, None) => None,
+        };
+    }
+    if let Some(prefix) = tail.strip_suffix(&[0xff]) {
+        return match decode_tagged_value(prefix) {
+            Ok((TaggableValue::String(s), [])) => Some(QueryBound::AfterPrefix(s)),
+            _ => None,
+        };
+    }
+    let (value, bound): (&[u8], fn(TaggableValue) -> QueryBound) = match tail {
+        [value @ .., 0x01] => (value, QueryBound::After),
+        [value @ .., 0x00] => (value, QueryBound::Before),
+        // Prefix start keys have no separator after the value
+        value => (value, QueryBound::Before),
+    };
+    match decode_tagged_value(value) {
+        Ok((v, [])) => Some(bound(v)),
+        _ => None,
+    }
+}
+
+// Returns whether k is an array size key, or a bound of a scan of them.
+pub fn is_array_size_key(k: &[u8]) -> bool {
+    k.first() == Some(&KEY_ARRAY_SIZE)
+}
+
+// Returns the first and last tags used to encode values of type t.
+fn type_tags(t: JsonType) -> (u8, u8) {
+    match t {
+        JsonType::Null => (JsonTag::Null as u8, JsonTag::Null as u8),
+        JsonType::Bool => (JsonTag::False as u8, JsonTag::True as u8),
+        JsonType::Number => (JsonTag::Number as u8, JsonTag::Number as u8),
+        JsonType::String => (JsonTag::String as u8, JsonTag::String as u8),
+    }
+}
+
 // Encodable is a small private trait that helps us encode
 // each type of value we use in our keys
 trait Encodable {
@@ -156,6 +524,8 @@ enum JsonTag {
     True = 0x2a,   // char: *
     Number = 0x2b, // char: +
     String = 0x2c, // char: ,
+    // Not a JSON type, AnyIndex is only used in paths
+    AnyIndex = 0x2d, // char: -
 }
 
 impl Encodable for TaggableValue {
@@ -191,10 +561,11 @@ impl Encodable for TaggableValue {
                 tv.push(JsonTag::String as u8);
                 tv.extend(s.as_bytes())
             }
-            TaggableValue::RcString(s) => {
+            TaggableValue::ArcString(s) => {
                 tv.push(JsonTag::String as u8);
                 tv.extend(s.as_bytes())
             }
+            TaggableValue::AnyIndex => tv.push(JsonTag::AnyIndex as u8),
         }
 
         tv
@@ -207,7 +578,7 @@ mod tests {
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn test_encode_document_key() {
@@ -225,6 +596,109 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_document_key() {
+        let k = encode_document_key("møkå");
+        assert_eq!(decode_document_key_docid(&k).unwrap(), "møkå");
+        assert!(encode_document_query_start_key() < k);
+        assert!(k < encode_document_query_end_key());
+        let k = encode_index_key("foo", &keypath!["pet"], &tv("cat"));
+        assert!(decode_document_key_docid(&k).is_err());
+    }
+
+    #[test]
+    fn test_encode_prefix_keys() {
+        let start = encode_index_query_prefix_start_key(&keypath!["name"], "jo");
+        let end = encode_index_query_prefix_end_key(&keypath!["name"], "jo");
+        for v in ["jo", "john", "jo\u{10ffff}"] {
+            let k = encode_index_key("foo", &keypath!["name"], &tv(v));
+            assert!(start <= k && k < end, "{} not in prefix range", v);
+        }
+        for v in ["j", "jn", "jp", "k"] {
+            let k = encode_index_key("foo", &keypath!["name"], &tv(v));
+            assert!(k < start || end <= k, "{} in prefix range", v);
+        }
+    }
+
+    #[test]
+    fn test_decode_index_key() {
+        let tests = vec![
+            (keypath!["phones", 1], tv("+44 2345678")),
+            (keypath!["pets", "bennie", "age"], tv(9)),
+            (keypath!["pet", TaggableValue::AnyIndex], tv(-1.5)),
+            (keypath!["a"], tv(0)),
+            (keypath!["a"], tv(true)),
+            (keypath!["a"], tv(false)),
+            (keypath!["a"], TaggableValue::Null),
+            (keypath!["a"], tv("")),
+        ];
+        for (p, v) in tests {
+            let k = encode_index_key("foo", &p, &v);
+            assert_eq!(decode_index_key(&k).unwrap(), (p, v, "foo".to_string()));
+        }
+        assert!(decode_index_key(&encode_document_key("foo")).is_err());
+    }
+
+    #[test]
+    fn test_is_key_at_path() {
+        let p = keypath!["a"];
+        let p_len = query_lower_bound(&p, None).len();
+        let tests = vec![
+            (keypath!["a"], tv("b"), true),
+            // The number's encoding has 0x00 bytes
+            (keypath!["a"], tv(1), true),
+            (keypath!["a"], TaggableValue::Null, true),
+            (keypath!["a", "b"], tv(1), false),
+            (keypath!["a", 0], tv("b"), false),
+            (keypath!["a", TaggableValue::AnyIndex], tv(5), false),
+        ];
+        for (path, v, expected) in tests {
+            let k = encode_index_key("foo", &path, &v);
+            assert_eq!(is_key_at_path(&k, p_len), expected, "{:?} {:?}", path, v);
+        }
+        let k = encode_array_size_key("foo", &p, 3);
+        assert!(is_key_at_path(&k, p_len));
+        let k = encode_array_size_key("foo", &keypath!["a", 0], 3);
+        assert!(!is_key_at_path(&k, p_len));
+    }
+
+    #[test]
+    fn test_encode_array_size_key() {
+        let k = encode_array_size_key("foo", &keypath!["pet"], 3);
+        assert_eq!(
+            k,
+            vec![
+                3, 0, // array size key
+                44, 112, 101, 116, 0, // string pet
+                43, 192, 8, 0, 0, 0, 0, 0, 0, 0, // number 3.0
+                44, 102, 111, 111 // string foo
+            ],
+        );
+        assert_eq!(decode_index_key_docid(&k).unwrap(), "foo");
+        let (p, v, docid) = decode_index_key(&k).unwrap();
+        assert_eq!((p, v, docid.as_str()), (keypath!["pet"], tv(3), "foo"));
+        assert!(array_size_query_lower_bound(&keypath!["pet"], Some(3)) < k);
+        assert!(k < array_size_query_upper_bound(&keypath!["pet"], Some(3)));
+        assert!(array_size_query_upper_bound(&keypath!["pet"], Some(2)) < k);
+        assert!(k < array_size_query_lower_bound(&keypath!["pet"], Some(4)));
+    }
+
+    #[test]
+    fn test_encode_text_keys() {
+        let k = encode_text_posting_key("foo", &keypath!["body"], "cat");
+        assert_eq!(decode_index_key_docid(&k).unwrap(), "foo");
+        assert!(text_posting_query_lower_bound(&keypath!["body"], "cat") < k);
+        assert!(k < text_posting_query_upper_bound(&keypath!["body"], "cat"));
+        let k = encode_text_posting_key("foo", &keypath!["body"], "cats");
+        assert!(k > text_posting_query_upper_bound(&keypath!["body"], "cat"));
+
+        let k = encode_text_length_key("foo", &keypath!["body"]);
+        assert_eq!(decode_index_key_docid(&k).unwrap(), "foo");
+        assert!(text_length_query_lower_bound(&keypath!["body"]) < k);
+        assert!(k < text_length_query_upper_bound(&keypath!["body"]));
+        assert!(k < text_length_query_lower_bound(&keypath!["body2"]));
+    }
+
     #[test]
     fn test_encode_null() {
         assert_eq!(TaggableValue::Null.encode(), vec![JsonTag::Null as u8]);
@@ -270,8 +744,8 @@ mod tests {
     fn test_encode_key() {
         assert_eq!(
             encode_index_key(
-                &"foo".to_string(),
-                &vec![tv(Rc::new("phones".to_string())), tv(1)],
+                "foo",
+                &vec![tv(Arc::new("phones".to_string())), tv(1)],
                 &tv("+44 2345678")
             ),
             vec![
@@ -296,11 +770,11 @@ mod tests {
     fn test_encode_key2() {
         assert_eq!(
             encode_index_key(
-                &"foo".to_string(),
+                "foo",
                 &vec![
-                    tv(Rc::new("pets".to_string())),
-                    tv(Rc::new("bennie".to_string())),
-                    tv(Rc::new("age".to_string())),
+                    tv(Arc::new("pets".to_string())),
+                    tv(Arc::new("bennie".to_string())),
+                    tv(Arc::new("age".to_string())),
                 ],
                 &tv(9),
             ),
@@ -325,10 +799,24 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_encode_any_index_key() {
+        assert_eq!(
+            encode_index_key("foo", &keypath!["pet", TaggableValue::AnyIndex], &tv("cat")),
+            vec![
+                2, 0, // index key
+                44, 112, 101, 116, 0, // string pet
+                45, 0, // any index
+                44, 99, 97, 116, 0, // string cat
+                44, 102, 111, 111 // string foo
+            ],
+        )
+    }
+
     #[test]
     fn test_encode_array_key() {
         assert_eq!(
-            encode_index_key(&"foo".to_string(), &keypath!["pet", 1], &tv("cat")),
+            encode_index_key("foo", &keypath!["pet", 1], &tv("cat")),
             vec![
                 2, 0, // index key
                 44, 112, 101, 116, 0, // string pet
@@ -338,4 +826,63 @@ mod tests {
             ],
         )
     }
+
+    #[test]
+    fn test_encode_ordinal_keys() {
+        let k = encode_ordinal_key(258);
+        assert_eq!(k, vec![9, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(decode_ordinal_key(&k).unwrap(), 258);
+        assert!(encode_ordinal_query_start_key() < k);
+        assert!(k < encode_ordinal_query_end_key());
+        assert!(k < encode_ordinal_key(259));
+        assert!(decode_ordinal(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_decode_query_bound() {
+        let p = keypath!["pet"];
+        let tests = vec![
+            (encode_index_query_p_start_key(&p), QueryBound::Start),
+            (encode_index_query_p_end_key(&p), QueryBound::End),
+            (
+                encode_index_query_pv_start_key(&p, &tv("cat")),
+                QueryBound::Before(tv("cat")),
+            ),
+            (
+                encode_index_query_pv_end_key(&p, &tv(1)),
+                QueryBound::After(tv(1)),
+            ),
+            (
+                encode_index_query_pv_end_key(&p, &tv("cat")),
+                QueryBound::After(tv("cat")),
+            ),
+            (
+                query_type_lower_bound(&p, JsonType::Bool),
+                QueryBound::BeforeType(JsonType::Bool),
+            ),
+            (
+                query_type_upper_bound(&p, JsonType::String),
+                QueryBound::AfterType(JsonType::String),
+            ),
+            (
+                encode_index_query_prefix_start_key(&p, "ca"),
+                QueryBound::Before(tv("ca")),
+            ),
+            (
+                encode_index_query_prefix_end_key(&p, "ca"),
+                QueryBound::AfterPrefix("ca".to_string()),
+            ),
+            (
+                array_size_query_lower_bound(&p, Some(2)),
+                QueryBound::Before(tv(2)),
+            ),
+        ];
+        for (k, expected) in tests {
+            assert_eq!(Some(expected), decode_query_bound(&p, &k));
+        }
+        // Keys for other paths
+        let k = encode_index_query_p_start_key(&keypath!["name"]);
+        assert_eq!(None, decode_query_bound(&p, &k));
+        assert!(is_array_size_key(&array_size_query_upper_bound(&p, None)));
+    }
 }
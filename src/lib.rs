@@ -1,4 +1,9 @@
+pub mod aggregate;
 pub mod docdb;
 mod encoding;
+mod parser;
 mod pathvalues;
 pub mod query;
+pub mod selector;
+mod stats;
+pub mod text;
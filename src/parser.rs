@@ -0,0 +1,864 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::docdb::DocDbError;
+use crate::query::{Cmp, JsonType, Query, TaggableValue, QP};
+
+// A small query language that compiles to QPs, eg:
+//
+//     name = "john" AND age > 20 AND (pet[*] = "cat" OR pet SIZE = 0)
+//
+// Paths are field names separated by dots, with array indexes in
+// brackets, eg, `a.b[0]`. `[*]` is TaggableValue::AnyIndex, and field
+// names that aren't identifiers can be quoted in brackets, eg,
+// `a["b c"]`. `$` is the empty path, for predicates on the elements
+// of an array within ELEMMATCH, and can start a path, eg, `$["and"]`.
+// A keyword can start a path when it's followed by `.`, `[` or a
+// comparison operator, eg, `type = "x"`. Otherwise it must be quoted,
+// eg, `$["type"] EXISTS`.
+//
+// Literals are JSON strings, numbers, true, false and null.
+//
+// Predicates:
+//
+//     p = v, p != v, p > v, p >= v, p < v, p <= v
+//     p IN [v, ...], p NOT IN [v, ...]
+//     p EXISTS, p MISSING
+//     p PREFIX "s", p MATCH "text"
+//     p TYPE null|bool|number|string
+//     p SIZE = n (or any other comparison operator)
+//     p ELEMMATCH (query)
+//
+// combined with NOT, AND and OR, in decreasing order of precedence.
+// Keywords are case-insensitive. The top-level AND terms are the
+// predicates of the returned Query. Parenthesised ANDs and ORs are
+// kept as QP::And and QP::Or, which is how QP is displayed, so that
+// parse(qp.to_string()) returns vec![qp] (as long as any And or Or
+// has at least two predicates).
+
+// An error for query strings that can't be parsed. span is the byte
+// range of the input where the error was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub span: Range<usize>,
+    pub message: String,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}..{}",
+            self.message, self.span.start, self.span.end
+        )
+    }
+}
+impl Error for ParseError {}
+
+impl From<ParseError> for DocDbError {
+    fn from(value: ParseError) -> Self {
+        DocDbError::InvalidQuery(value.to_string())
+    }
+}
+
+impl ParseError {
+    // Returns the error message with the part of input it refers to
+    // underlined, eg:
+    //
+    //     age >
+    //          ^ expected a value
+    pub fn annotate(&self, input: &str) -> String {
+        let start = input[..self.span.start].chars().count();
+        let width = input[self.span.clone()].chars().count().max(1);
+        format!(
+            "{}\n{}{} {}",
+            input,
+            " ".repeat(start),
+            "^".repeat(width),
+            self.message
+        )
+    }
+}
+
+// Parses a query string into a Query.
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    let mut parser = Parser {
+        tokens: lex(input)?,
+        pos: 0,
+        end: input.len(),
+    };
+    let q = parser.parse_query()?;
+    match parser.peek() {
+        None => Ok(q),
+        Some(_) => Err(parser.error("expected AND, OR or the end of the query")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Sym(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "!=", ">=", "<=", "=", ">", "<", "(", ")", "[", "]", ",", ".", "*", "$",
+];
+
+const KEYWORDS: &[&str] = &[
+    "and",
+    "or",
+    "not",
+    "in",
+    "exists",
+    "missing",
+    "prefix",
+    "match",
+    "type",
+    "size",
+    "elemmatch",
+    "true",
+    "false",
+    "null",
+];
+
+fn lex(input: &str) -> Result<Vec<(Tok, Range<usize>)>, ParseError> {
+    let mut tokens = vec![];
+    let bytes = input.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = input[i..].chars().next().unwrap();
+        let tok = if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            Tok::Ident(input[start..i].to_string())
+        } else if c.is_ascii_digit()
+            || (c == '-' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            if bytes.get(i) == Some(&b'.') && bytes.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            if matches!(bytes.get(i), Some(b'e' | b'E')) {
+                let mut j = i + 1;
+                if matches!(bytes.get(j), Some(b'+' | b'-')) {
+                    j += 1;
+                }
+                if bytes.get(j).is_some_and(u8::is_ascii_digit) {
+                    i = j;
+                    while i < bytes.len() && bytes[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            match input[start..i].parse() {
+                Ok(n) => Tok::Num(n),
+                Err(_) => {
+                    return Err(ParseError {
+                        span: start..i,
+                        message: "invalid number".to_string(),
+                    })
+                }
+            }
+        } else if c == '"' {
+            // Find the closing quote, then let serde_json handle escapes
+            i += 1;
+            loop {
+                match bytes.get(i) {
+                    Some(b'"') => break,
+                    Some(b'\\') => i += 2,
+                    Some(_) => i += 1,
+                    None => {
+                        return Err(ParseError {
+                            span: start..input.len(),
+                            message: "unterminated string".to_string(),
+                        })
+                    }
+                }
+            }
+            i += 1;
+            match serde_json::from_str(&input[start..i]) {
+                Ok(s) => Tok::Str(s),
+                Err(_) => {
+                    return Err(ParseError {
+                        span: start..i,
+                        message: "invalid string".to_string(),
+                    })
+                }
+            }
+        } else {
+            match SYMBOLS.iter().find(|s| input[i..].starts_with(*s)) {
+                Some(s) => {
+                    i += s.len();
+                    Tok::Sym(s)
+                }
+                None => {
+                    return Err(ParseError {
+                        span: start..start + c.len_utf8(),
+                        message: format!("unexpected character `{}`", c),
+                    })
+                }
+            }
+        };
+        tokens.push((tok, start..i));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Tok, Range<usize>)>,
+    pos: usize,
+    // The length of the input, for errors at its end
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let t = self.peek().cloned();
+        self.pos += 1;
+        t
+    }
+
+    // Returns an error for the current token
+    fn error(&self, message: &str) -> ParseError {
+        let span = match self.tokens.get(self.pos) {
+            Some((_, span)) => span.clone(),
+            None => self.end..self.end,
+        };
+        let message = match self.tokens.get(self.pos) {
+            Some(_) => message.to_string(),
+            None => format!("{}, found the end of the query", message),
+        };
+        ParseError { span, message }
+    }
+
+    fn is_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(s)) if s.eq_ignore_ascii_case(kw))
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        let found = self.is_keyword(kw);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        let found = matches!(self.peek(), Some(Tok::Sym(s)) if *s == sym);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), ParseError> {
+        match self.eat_sym(sym) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected `{}`", sym))),
+        }
+    }
+
+    // query := and (OR and)*
+    // As AND binds tighter than OR, a query without OR is returned
+    // as its AND terms, and one with OR as a single QP::Or.
+    fn parse_query(&mut self) -> Result<Query, ParseError> {
+        let mut groups = vec![self.parse_and()?];
+        while self.eat_keyword("or") {
+            groups.push(self.parse_and()?);
+        }
+        if groups.len() == 1 {
+            return Ok(groups.remove(0));
+        }
+        Ok(vec![QP::Or(groups.into_iter().map(conjunction).collect())])
+    }
+
+    // and := unary (AND unary)*
+    fn parse_and(&mut self) -> Result<Vec<QP>, ParseError> {
+        let mut qps = vec![self.parse_unary()?];
+        while self.eat_keyword("and") {
+            qps.push(self.parse_unary()?);
+        }
+        Ok(qps)
+    }
+
+    // unary := NOT unary | ( query ) | predicate
+    fn parse_unary(&mut self) -> Result<QP, ParseError> {
+        if !self.keyword_starts_path() && self.eat_keyword("not") {
+            return Ok(QP::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat_sym("(") {
+            let qps = self.parse_query()?;
+            self.expect_sym(")")?;
+            return Ok(conjunction(qps));
+        }
+        self.parse_predicate()
+    }
+
+    fn parse_predicate(&mut self) -> Result<QP, ParseError> {
+        let p = self.parse_path()?;
+        if let Some(op) = self.parse_cmp() {
+            let v = self.parse_value()?;
+            return Ok(match op {
+                "=" => QP::E { p, v },
+                "!=" => QP::NE { p, v },
+                ">" => QP::GT { p, v },
+                ">=" => QP::GTE { p, v },
+                "<" => QP::LT { p, v },
+                _ => QP::LTE { p, v },
+            });
+        }
+        if self.eat_keyword("in") {
+            return Ok(QP::In {
+                p,
+                vs: self.parse_values()?,
+            });
+        }
+        if self.eat_keyword("not") {
+            if !self.eat_keyword("in") {
+                return Err(self.error("expected IN"));
+            }
+            return Ok(QP::NotIn {
+                p,
+                vs: self.parse_values()?,
+            });
+        }
+        if self.eat_keyword("exists") {
+            return Ok(QP::Exists { p });
+        }
+        if self.eat_keyword("missing") {
+            return Ok(QP::Missing { p });
+        }
+        if self.eat_keyword("prefix") {
+            return Ok(QP::Prefix {
+                p,
+                prefix: self.parse_string()?,
+            });
+        }
+        if self.eat_keyword("match") {
+            return Ok(QP::Match {
+                p,
+                q: self.parse_string()?,
+            });
+        }
+        if self.eat_keyword("type") {
+            let t = match self.peek() {
+                Some(Tok::Ident(t)) => match t.to_ascii_lowercase().as_str() {
+                    "null" => Some(JsonType::Null),
+                    "bool" => Some(JsonType::Bool),
+                    "number" => Some(JsonType::Number),
+                    "string" => Some(JsonType::String),
+                    _ => None,
+                },
+                _ => None,
+            };
+            return match t {
+                Some(t) => {
+                    self.pos += 1;
+                    Ok(QP::Type { p, t })
+                }
+                None => Err(self.error("expected null, bool, number or string")),
+            };
+        }
+        if self.eat_keyword("size") {
+            let op = match self.parse_cmp() {
+                Some("=") => Cmp::E,
+                Some(">") => Cmp::GT,
+                Some(">=") => Cmp::GTE,
+                Some("<") => Cmp::LT,
+                Some("<=") => Cmp::LTE,
+                _ => return Err(self.error("expected =, >, >=, < or <=")),
+            };
+            return match self.peek() {
+                Some(Tok::Num(n)) if *n >= 0.0 && n.fract() == 0.0 => {
+                    let n = *n as usize;
+                    self.pos += 1;
+                    Ok(QP::Size { p, op, n })
+                }
+                _ => Err(self.error("expected a non-negative integer")),
+            };
+        }
+        if self.eat_keyword("elemmatch") {
+            self.expect_sym("(")?;
+            let qps = match self.eat_sym(")") {
+                true => vec![],
+                false => {
+                    let qps = self.parse_query()?;
+                    self.expect_sym(")")?;
+                    qps
+                }
+            };
+            return Ok(QP::ElemMatch { p, qps });
+        }
+        Err(self.error("expected an operator"))
+    }
+
+    // Consumes a comparison operator, if there is one
+    fn parse_cmp(&mut self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Tok::Sym(s)) if ["=", "!=", ">", ">=", "<", "<="].contains(s) => {
+                let s = *s;
+                self.pos += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether the current token, if it's a keyword, is the first
+    // field of a path instead, as the token after it continues the
+    // path or is a comparison operator.
+    fn keyword_starts_path(&self) -> bool {
+        matches!(
+            self.tokens.get(self.pos + 1),
+            Some((Tok::Sym(s), _)) if [".", "[", "=", "!=", ">", ">=", "<", "<="].contains(s)
+        )
+    }
+
+    // path := (ident | $) ( . ident | [ * ] | [ value ] )*
+    fn parse_path(&mut self) -> Result<Vec<TaggableValue>, ParseError> {
+        let mut p = vec![];
+        match self.peek() {
+            Some(Tok::Sym("$")) => self.pos += 1,
+            Some(Tok::Ident(s)) if !is_keyword(s) || self.keyword_starts_path() => {
+                p.push(TaggableValue::from(s.as_str()));
+                self.pos += 1;
+            }
+            _ => return Err(self.error("expected a path")),
+        }
+        loop {
+            if self.eat_sym(".") {
+                match self.next() {
+                    Some(Tok::Ident(s)) => p.push(TaggableValue::from(s)),
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.error("expected a field name"));
+                    }
+                }
+            } else if self.eat_sym("[") {
+                if self.eat_sym("*") {
+                    p.push(TaggableValue::AnyIndex);
+                } else {
+                    p.push(self.parse_value()?);
+                }
+                self.expect_sym("]")?;
+            } else {
+                return Ok(p);
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<TaggableValue, ParseError> {
+        let v = match self.peek() {
+            Some(Tok::Str(s)) => TaggableValue::from(s.as_str()),
+            Some(Tok::Num(n)) => TaggableValue::from(*n),
+            Some(Tok::Ident(s)) if s.eq_ignore_ascii_case("true") => TaggableValue::from(true),
+            Some(Tok::Ident(s)) if s.eq_ignore_ascii_case("false") => TaggableValue::from(false),
+            Some(Tok::Ident(s)) if s.eq_ignore_ascii_case("null") => TaggableValue::Null,
+            _ => return Err(self.error("expected a value")),
+        };
+        self.pos += 1;
+        Ok(v)
+    }
+
+    // values := [ value (, value)* ]
+    fn parse_values(&mut self) -> Result<Vec<TaggableValue>, ParseError> {
+        self.expect_sym("[")?;
+        let mut vs = vec![];
+        if self.eat_sym("]") {
+            return Ok(vs);
+        }
+        loop {
+            vs.push(self.parse_value()?);
+            if self.eat_sym("]") {
+                return Ok(vs);
+            }
+            if !self.eat_sym(",") {
+                return Err(self.error("expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Tok::Str(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => Err(self.error("expected a string")),
+        }
+    }
+}
+
+// A single predicate that is the AND of qps.
+fn conjunction(mut qps: Vec<QP>) -> QP {
+    if qps.len() == 1 {
+        qps.remove(0)
+    } else {
+        QP::And(qps)
+    }
+}
+
+fn is_keyword(s: &str) -> bool {
+    KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(s))
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !is_keyword(s)
+}
+
+pub(crate) struct DisplayValue<'a>(pub(crate) &'a TaggableValue);
+
+impl fmt::Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self.0 {
+            TaggableValue::String(s) => s.as_str(),
+            TaggableValue::ArcString(s) => s.as_str(),
+            TaggableValue::Null => return write!(f, "null"),
+            TaggableValue::Bool(b) => return write!(f, "{}", b),
+            TaggableValue::Number(n) => return write!(f, "{}", n),
+            TaggableValue::AnyIndex => return write!(f, "*"),
+        };
+        match serde_json::to_string(s) {
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => Err(fmt::Error),
+        }
+    }
+}
+
+pub(crate) struct DisplayPath<'a>(pub(crate) &'a [TaggableValue]);
+
+impl fmt::Display for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, c) in self.0.iter().enumerate() {
+            let name = match c {
+                TaggableValue::String(s) => Some(s.as_str()),
+                TaggableValue::ArcString(s) => Some(s.as_str()),
+                _ => None,
+            };
+            match name {
+                Some(s) if is_identifier(s) && i == 0 => write!(f, "{}", s)?,
+                Some(s) if is_identifier(s) => write!(f, ".{}", s)?,
+                _ => {
+                    if i == 0 {
+                        write!(f, "$")?;
+                    }
+                    write!(f, "[{}]", DisplayValue(c))?;
+                }
+            }
+        }
+        if self.0.is_empty() {
+            write!(f, "$")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct DisplayValues<'a>(&'a [TaggableValue]);
+
+impl fmt::Display for DisplayValues<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, v) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", DisplayValue(v))?;
+        }
+        write!(f, "]")
+    }
+}
+
+fn write_joined(f: &mut fmt::Formatter<'_>, qps: &[QP], sep: &str) -> fmt::Result {
+    for (i, qp) in qps.iter().enumerate() {
+        if i > 0 {
+            write!(f, " {} ", sep)?;
+        }
+        write!(f, "{}", qp)?;
+    }
+    Ok(())
+}
+
+// Displays a QP in the query language. And and Or are always
+// parenthesised, so the output parses back to the same QP.
+impl fmt::Display for QP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QP::E { p, v } => write!(f, "{} = {}", DisplayPath(p), DisplayValue(v)),
+            QP::NE { p, v } => write!(f, "{} != {}", DisplayPath(p), DisplayValue(v)),
+            QP::GT { p, v } => write!(f, "{} > {}", DisplayPath(p), DisplayValue(v)),
+            QP::GTE { p, v } => write!(f, "{} >= {}", DisplayPath(p), DisplayValue(v)),
+            QP::LT { p, v } => write!(f, "{} < {}", DisplayPath(p), DisplayValue(v)),
+            QP::LTE { p, v } => write!(f, "{} <= {}", DisplayPath(p), DisplayValue(v)),
+            QP::In { p, vs } => write!(f, "{} IN {}", DisplayPath(p), DisplayValues(vs)),
+            QP::NotIn { p, vs } => write!(f, "{} NOT IN {}", DisplayPath(p), DisplayValues(vs)),
+            QP::Prefix { p, prefix } => write!(
+                f,
+                "{} PREFIX {}",
+                DisplayPath(p),
+                DisplayValue(&TaggableValue::from(prefix.as_str()))
+            ),
+            QP::Match { p, q } => write!(
+                f,
+                "{} MATCH {}",
+                DisplayPath(p),
+                DisplayValue(&TaggableValue::from(q.as_str()))
+            ),
+            QP::Exists { p } => write!(f, "{} EXISTS", DisplayPath(p)),
+            QP::Missing { p } => write!(f, "{} MISSING", DisplayPath(p)),
+            QP::Type { p, t } => {
+                let t = match t {
+                    JsonType::Null => "null",
+                    JsonType::Bool => "bool",
+                    JsonType::Number => "number",
+                    JsonType::String => "string",
+                };
+                write!(f, "{} TYPE {}", DisplayPath(p), t)
+            }
+            QP::Size { p, op, n } => {
+                let op = match op {
+                    Cmp::E => "=",
+                    Cmp::GT => ">",
+                    Cmp::GTE => ">=",
+                    Cmp::LT => "<",
+                    Cmp::LTE => "<=",
+                };
+                write!(f, "{} SIZE {} {}", DisplayPath(p), op, n)
+            }
+            QP::ElemMatch { p, qps } => {
+                write!(f, "{} ELEMMATCH (", DisplayPath(p))?;
+                write_joined(f, qps, "AND")?;
+                write!(f, ")")
+            }
+            QP::And(qps) => {
+                write!(f, "(")?;
+                write_joined(f, qps, "AND")?;
+                write!(f, ")")
+            }
+            QP::Or(qps) => {
+                write!(f, "(")?;
+                write_joined(f, qps, "OR")?;
+                write!(f, ")")
+            }
+            QP::Not(qp) => write!(f, "NOT {}", qp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypath;
+    use crate::query::tv;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(r#"name = "john" AND age > 20 AND a.c <= 2"#).unwrap(),
+            vec![
+                QP::E {
+                    p: keypath!["name"],
+                    v: tv("john")
+                },
+                QP::GT {
+                    p: keypath!["age"],
+                    v: tv(20)
+                },
+                QP::LTE {
+                    p: keypath!["a", "c"],
+                    v: tv(2)
+                },
+            ]
+        );
+        assert_eq!(
+            parse(r#"pet[*] in ["cat", null] and $["a b"][0] != -1.5e1 and not x exists"#).unwrap(),
+            vec![
+                QP::In {
+                    p: keypath!["pet", TaggableValue::AnyIndex],
+                    vs: vec![tv("cat"), TaggableValue::Null]
+                },
+                QP::NE {
+                    p: keypath!["a b", 0],
+                    v: tv(-15.0)
+                },
+                QP::Not(Box::new(QP::Exists { p: keypath!["x"] })),
+            ]
+        );
+        // Keywords can start a path when followed by a comparison
+        // operator, `.` or `[`, and otherwise are quoted
+        assert_eq!(
+            parse(r#"type = "x" AND not size > 2 AND match.in[0] = 1 AND $["type"] EXISTS"#)
+                .unwrap(),
+            vec![
+                QP::E {
+                    p: keypath!["type"],
+                    v: tv("x")
+                },
+                QP::Not(Box::new(QP::GT {
+                    p: keypath!["size"],
+                    v: tv(2)
+                })),
+                QP::E {
+                    p: keypath!["match", "in", 0],
+                    v: tv(1)
+                },
+                QP::Exists {
+                    p: keypath!["type"]
+                },
+            ]
+        );
+        assert_eq!(
+            parse("not = 1").unwrap(),
+            vec![QP::E {
+                p: keypath!["not"],
+                v: tv(1)
+            }]
+        );
+        // AND binds tighter than OR
+        assert_eq!(
+            parse("a = 1 AND b = true OR c = false").unwrap(),
+            vec![QP::Or(vec![
+                QP::And(vec![
+                    QP::E {
+                        p: keypath!["a"],
+                        v: tv(1)
+                    },
+                    QP::E {
+                        p: keypath!["b"],
+                        v: tv(true)
+                    },
+                ]),
+                QP::E {
+                    p: keypath!["c"],
+                    v: tv(false)
+                },
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let e = |s: &str| parse(s).unwrap_err();
+        assert_eq!(
+            e("age >"),
+            ParseError {
+                span: 5..5,
+                message: "expected a value, found the end of the query".to_string()
+            }
+        );
+        assert_eq!(e("age > 1 name = 2").span, 8..12);
+        assert_eq!(e("age ~ 1").span, 4..5);
+        assert_eq!(e(r#"name = "john"#).span, 7..12);
+        assert_eq!(e("pet SIZE = 1.5").span, 11..14);
+        assert_eq!(e("and exists").message, "expected a path");
+        assert_eq!(e("type TYPE string").message, "expected a path");
+        assert_eq!(
+            e("(a = 1").to_string(),
+            "expected `)`, found the end of the query at 6..6"
+        );
+        assert_eq!(
+            e("age IN [1 2]").annotate("age IN [1 2]"),
+            "age IN [1 2]\n          ^ expected `,` or `]`"
+        );
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let qps = vec![
+            QP::E {
+                p: keypath!["a", "b", 0],
+                v: tv("say \"hi\"\n"),
+            },
+            QP::GTE {
+                p: keypath!["a b", "and", TaggableValue::AnyIndex],
+                v: tv(0.25),
+            },
+            QP::LT {
+                p: keypath![1, "x"],
+                v: TaggableValue::Null,
+            },
+            QP::NotIn {
+                p: keypath!["age"],
+                vs: vec![tv(1), tv(true), tv("x")],
+            },
+            QP::Prefix {
+                p: keypath!["name"],
+                prefix: "jo".to_string(),
+            },
+            QP::Match {
+                p: keypath!["body"],
+                q: "quick fox".to_string(),
+            },
+            QP::Type {
+                p: keypath!["age"],
+                t: JsonType::Number,
+            },
+            QP::Size {
+                p: keypath!["pet"],
+                op: Cmp::GTE,
+                n: 2,
+            },
+            QP::ElemMatch {
+                p: keypath!["pet"],
+                qps: vec![
+                    QP::E {
+                        p: keypath![],
+                        v: tv("cat"),
+                    },
+                    QP::Missing { p: keypath!["x"] },
+                ],
+            },
+            QP::Or(vec![
+                QP::And(vec![
+                    QP::Exists { p: keypath!["a"] },
+                    QP::Not(Box::new(QP::Or(vec![
+                        QP::NE {
+                            p: keypath!["b"],
+                            v: tv(-1),
+                        },
+                        QP::LTE {
+                            p: keypath!["c"],
+                            v: tv(1e21),
+                        },
+                    ]))),
+                ]),
+                QP::In {
+                    p: keypath!["d"],
+                    vs: vec![],
+                },
+            ]),
+        ];
+        for qp in qps {
+            let s = qp.to_string();
+            assert_eq!(parse(&s).unwrap(), vec![qp], "{}", s);
+        }
+        assert_eq!(
+            QP::GTE {
+                p: keypath!["a b", "and", TaggableValue::AnyIndex, "c"],
+                v: tv(0.25),
+            }
+            .to_string(),
+            r#"$["a b"]["and"][*].c >= 0.25"#
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::query::TaggableValue;
 use serde_json::Value;
-use std::rc::Rc;
+use std::sync::Arc;
 
 // get_path_values returns a Vector of (path, value) tuples. We use the json_serde::Value type
 // so we carry around some type information for later encoding.
@@ -21,7 +21,7 @@ pub fn get_path_values(v: Value) -> Vec<(Vec<TaggableValue>, TaggableValue)> {
             Value::Object(o) => {
                 for (k, v) in o {
                     let mut p = path.clone();
-                    p.push(TaggableValue::RcString(Rc::new(k)));
+                    p.push(TaggableValue::ArcString(Arc::new(k)));
                     stack.push((p, v))
                 }
             }
@@ -38,11 +38,156 @@ pub fn get_path_values(v: Value) -> Vec<(Vec<TaggableValue>, TaggableValue)> {
     acc
 }
 
+// get_indexed_path_values returns the path values from get_path_values plus,
+// for each path that passes through an array, a copy of the path value
+// with every array index replaced with AnyIndex. Indexing these extra
+// entries allows queries to match any element of an array without knowing
+// its position.
+pub fn get_indexed_path_values(v: Value) -> Vec<(Vec<TaggableValue>, TaggableValue)> {
+    let mut acc = get_path_values(v);
+    let any_index: Vec<_> = acc
+        .iter()
+        .filter_map(|(path, v)| any_index_path(path).map(|p| (p, v.clone())))
+        .collect();
+    acc.extend(any_index);
+    acc
+}
+
+// get_array_sizes returns a Vector of (path, length) tuples for every array in v,
+// including empty arrays which get_path_values drops. As with
+// get_indexed_path_values, arrays within arrays are also returned with their
+// array indexes replaced with AnyIndex.
+pub fn get_array_sizes(v: &Value) -> Vec<(Vec<TaggableValue>, usize)> {
+    let mut acc = vec![];
+    let mut stack = vec![(vec![], v)];
+
+    while let Some((path, v)) = stack.pop() {
+        match v {
+            Value::Array(a) => {
+                acc.push((path.clone(), a.len()));
+                for (i, v) in a.iter().enumerate() {
+                    let mut p = path.clone();
+                    p.push(TaggableValue::Number(i as f64));
+                    stack.push((p, v))
+                }
+            }
+            Value::Object(o) => {
+                for (k, v) in o {
+                    let mut p = path.clone();
+                    p.push(TaggableValue::ArcString(Arc::new(k.clone())));
+                    stack.push((p, v))
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let any_index: Vec<_> = acc
+        .iter()
+        .filter_map(|(path, n)| any_index_path(path).map(|p| (p, *n)))
+        .collect();
+    acc.extend(any_index);
+    acc
+}
+
+// Returns a copy of path with every array index replaced with
+// AnyIndex, or None if the path doesn't pass through an array.
+fn any_index_path(path: &[TaggableValue]) -> Option<Vec<TaggableValue>> {
+    if !path.iter().any(|c| matches!(c, TaggableValue::Number(_))) {
+        return None;
+    }
+    let p = path
+        .iter()
+        .map(|c| match c {
+            TaggableValue::Number(_) => TaggableValue::AnyIndex,
+            c => c.clone(),
+        })
+        .collect();
+    Some(p)
+}
+
+// path_matches returns whether path is the same as pattern, where an
+// AnyIndex component of pattern matches any array index. path is of a
+// value in a document, so AnyIndex in path doesn't match.
+pub fn path_matches(pattern: &[TaggableValue], path: &[TaggableValue]) -> bool {
+    pattern.len() == path.len()
+        && pattern.iter().zip(path).all(|(c, pc)| match (c, pc) {
+            (TaggableValue::AnyIndex, pc) => matches!(pc, TaggableValue::Number(_)),
+            _ => match (field_name(c), field_name(pc)) {
+                (Some(name), Some(p_name)) => name == p_name,
+                _ => c == pc,
+            },
+        })
+}
+
+fn field_name(c: &TaggableValue) -> Option<&str> {
+    match c {
+        TaggableValue::String(s) => Some(s.as_str()),
+        TaggableValue::ArcString(s) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+// project returns the parts of v at the given paths, keeping their
+// place in the document, or None if v has none of them. Array indexes
+// select single elements and AnyIndex every element; the selected
+// elements are returned in order in a shorter array.
+pub fn project(v: Value, paths: &[&[TaggableValue]]) -> Option<Value> {
+    if paths.iter().any(|p| p.is_empty()) {
+        return Some(v);
+    }
+    match v {
+        Value::Object(o) => {
+            let mut projected = serde_json::Map::new();
+            for (k, v) in o {
+                let rest: Vec<&[TaggableValue]> = paths
+                    .iter()
+                    .filter_map(|p| match &p[0] {
+                        TaggableValue::String(s) if *s == k => Some(&p[1..]),
+                        TaggableValue::ArcString(s) if **s == k => Some(&p[1..]),
+                        _ => None,
+                    })
+                    .collect();
+                if rest.is_empty() {
+                    continue;
+                }
+                if let Some(v) = project(v, &rest) {
+                    projected.insert(k, v);
+                }
+            }
+            (!projected.is_empty()).then_some(Value::Object(projected))
+        }
+        Value::Array(a) => {
+            let mut projected = vec![];
+            for (i, v) in a.into_iter().enumerate() {
+                let rest: Vec<&[TaggableValue]> = paths
+                    .iter()
+                    .filter_map(|p| match &p[0] {
+                        TaggableValue::AnyIndex => Some(&p[1..]),
+                        TaggableValue::Number(n) if *n == i as f64 => Some(&p[1..]),
+                        _ => None,
+                    })
+                    .collect();
+                if rest.is_empty() {
+                    continue;
+                }
+                if let Some(v) = project(v, &rest) {
+                    projected.push(v);
+                }
+            }
+            (!projected.is_empty()).then_some(Value::Array(projected))
+        }
+        // The paths go below a value that has no fields
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
 
     use super::*;
+    use crate::keypath;
 
     #[test]
     fn test_get_path_value() {
@@ -62,60 +207,211 @@ mod tests {
         let expected = vec![
             (
                 vec![
-                    TaggableValue::RcString(Rc::new("phones".to_string())),
+                    TaggableValue::ArcString(Arc::new("phones".to_string())),
                     TaggableValue::Number(1.0),
                 ],
                 TaggableValue::String("+44 2345678".to_string()),
             ),
             (
                 vec![
-                    TaggableValue::RcString(Rc::new("phones".to_string())),
+                    TaggableValue::ArcString(Arc::new("phones".to_string())),
                     TaggableValue::Number(0.0),
                 ],
                 TaggableValue::String("+44 1234567".to_string()),
             ),
             (
                 vec![
-                    TaggableValue::RcString(Rc::new("pets".to_string())),
-                    TaggableValue::RcString(Rc::new("frankie".to_string())),
-                    TaggableValue::RcString(Rc::new("species".to_string())),
+                    TaggableValue::ArcString(Arc::new("pets".to_string())),
+                    TaggableValue::ArcString(Arc::new("frankie".to_string())),
+                    TaggableValue::ArcString(Arc::new("species".to_string())),
                 ],
                 TaggableValue::String("cat".to_string()),
             ),
             (
                 vec![
-                    TaggableValue::RcString(Rc::new("pets".to_string())),
-                    TaggableValue::RcString(Rc::new("frankie".to_string())),
-                    TaggableValue::RcString(Rc::new("age".to_string())),
+                    TaggableValue::ArcString(Arc::new("pets".to_string())),
+                    TaggableValue::ArcString(Arc::new("frankie".to_string())),
+                    TaggableValue::ArcString(Arc::new("age".to_string())),
                 ],
                 TaggableValue::Number(3.0),
             ),
             (
                 vec![
-                    TaggableValue::RcString(Rc::new("pets".to_string())),
-                    TaggableValue::RcString(Rc::new("bennie".to_string())),
-                    TaggableValue::RcString(Rc::new("species".to_string())),
+                    TaggableValue::ArcString(Arc::new("pets".to_string())),
+                    TaggableValue::ArcString(Arc::new("bennie".to_string())),
+                    TaggableValue::ArcString(Arc::new("species".to_string())),
                 ],
                 TaggableValue::String("cat".to_string()),
             ),
             (
                 vec![
-                    TaggableValue::RcString(Rc::new("pets".to_string())),
-                    TaggableValue::RcString(Rc::new("bennie".to_string())),
-                    TaggableValue::RcString(Rc::new("age".to_string())),
+                    TaggableValue::ArcString(Arc::new("pets".to_string())),
+                    TaggableValue::ArcString(Arc::new("bennie".to_string())),
+                    TaggableValue::ArcString(Arc::new("age".to_string())),
                 ],
                 TaggableValue::Number(9.0),
             ),
             (
-                vec![TaggableValue::RcString(Rc::new("name".to_string()))],
+                vec![TaggableValue::ArcString(Arc::new("name".to_string()))],
                 TaggableValue::String("John Doe".to_string()),
             ),
             (
-                vec![TaggableValue::RcString(Rc::new("age".to_string()))],
+                vec![TaggableValue::ArcString(Arc::new("age".to_string()))],
                 TaggableValue::Number(43.0),
             ),
         ];
 
         assert_eq!(path_values, expected);
     }
+
+    #[test]
+    fn test_get_indexed_path_value() {
+        let v = json!({
+            "name": "John Doe",
+            "orders": [{"total": 10}, {"total": 250}],
+        });
+        let path_values = get_indexed_path_values(v);
+        let orders = TaggableValue::ArcString(Arc::new("orders".to_string()));
+        let total = TaggableValue::ArcString(Arc::new("total".to_string()));
+        let expected = vec![
+            (
+                vec![orders.clone(), TaggableValue::Number(1.0), total.clone()],
+                TaggableValue::Number(250.0),
+            ),
+            (
+                vec![orders.clone(), TaggableValue::Number(0.0), total.clone()],
+                TaggableValue::Number(10.0),
+            ),
+            (
+                vec![TaggableValue::ArcString(Arc::new("name".to_string()))],
+                TaggableValue::String("John Doe".to_string()),
+            ),
+            (
+                vec![orders.clone(), TaggableValue::AnyIndex, total.clone()],
+                TaggableValue::Number(250.0),
+            ),
+            (
+                vec![orders, TaggableValue::AnyIndex, total],
+                TaggableValue::Number(10.0),
+            ),
+        ];
+
+        assert_eq!(path_values, expected);
+    }
+
+    #[test]
+    fn test_get_array_sizes() {
+        let v = json!({
+            "tags": [],
+            "orders": [{"items": [1, 2, 3]}, {"items": [[4]]}],
+            "name": "John Doe",
+        });
+        let sizes = get_array_sizes(&v);
+        let orders = TaggableValue::ArcString(Arc::new("orders".to_string()));
+        let items = TaggableValue::ArcString(Arc::new("items".to_string()));
+        let expected = vec![
+            (
+                vec![TaggableValue::ArcString(Arc::new("tags".to_string()))],
+                0,
+            ),
+            (vec![orders.clone()], 2),
+            (
+                vec![orders.clone(), TaggableValue::Number(1.0), items.clone()],
+                1,
+            ),
+            (
+                vec![
+                    orders.clone(),
+                    TaggableValue::Number(1.0),
+                    items.clone(),
+                    TaggableValue::Number(0.0),
+                ],
+                1,
+            ),
+            (
+                vec![orders.clone(), TaggableValue::Number(0.0), items.clone()],
+                3,
+            ),
+            (
+                vec![orders.clone(), TaggableValue::AnyIndex, items.clone()],
+                1,
+            ),
+            (
+                vec![
+                    orders.clone(),
+                    TaggableValue::AnyIndex,
+                    items.clone(),
+                    TaggableValue::AnyIndex,
+                ],
+                1,
+            ),
+            (vec![orders, TaggableValue::AnyIndex, items], 3),
+        ];
+
+        assert_eq!(sizes, expected);
+    }
+
+    #[test]
+    fn test_project() {
+        let v = json!({
+            "name": "John Doe",
+            "age": 43,
+            "phones": ["+44 1234567", "+44 2345678"],
+            "pets": [
+                {"name": "frankie", "species": "cat", "age": 3},
+                {"name": "bennie", "species": "dog", "age": 9},
+            ],
+        });
+        let p = |paths: Vec<Vec<TaggableValue>>| {
+            let paths: Vec<&[TaggableValue]> = paths.iter().map(|p| p.as_slice()).collect();
+            project(v.clone(), &paths)
+        };
+        assert_eq!(
+            p(vec![
+                keypath!["name"],
+                keypath!["phones", 1],
+                keypath!["email"]
+            ]),
+            Some(json!({"name": "John Doe", "phones": ["+44 2345678"]}))
+        );
+        assert_eq!(
+            p(vec![
+                keypath!["pets", TaggableValue::AnyIndex, "name"],
+                keypath!["pets", 1, "age"],
+            ]),
+            Some(json!({"pets": [{"name": "frankie"}, {"name": "bennie", "age": 9}]}))
+        );
+        assert_eq!(p(vec![keypath![]]), Some(v.clone()));
+        assert_eq!(p(vec![keypath!["age", "years"]]), None);
+        assert_eq!(p(vec![]), None);
+    }
+
+    #[test]
+    fn test_path_matches() {
+        let any = TaggableValue::AnyIndex;
+        let path = keypath!["orders", 1, "items", 0];
+        assert!(path_matches(&path, &path));
+        assert!(path_matches(
+            &keypath!["orders", any.clone(), "items", any.clone()],
+            &path
+        ));
+        assert!(path_matches(
+            &keypath!["orders", 1, "items", any.clone()],
+            &path
+        ));
+        assert!(!path_matches(
+            &keypath!["orders", 0, "items", any.clone()],
+            &path
+        ));
+        assert!(!path_matches(
+            &keypath!["orders", any.clone(), "items"],
+            &path
+        ));
+        // Field names match whichever way their strings are held
+        let arc = vec![TaggableValue::ArcString(Arc::new("a".to_string()))];
+        assert!(path_matches(&keypath!["a"], &arc));
+        // AnyIndex only matches array indexes
+        assert!(!path_matches(&keypath![any.clone()], &keypath!["a"]));
+        assert!(!path_matches(&keypath![any.clone()], &keypath![any]));
+    }
 }
@@ -1,20 +1,68 @@
-use std::{collections::BTreeMap, rc::Rc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Bound,
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use roaring::RoaringTreemap;
+use serde_json::{json, Value};
 use sled::Db;
 
 use crate::{
-    docdb::DocDbError,
+    docdb::{self, DocDbError},
     encoding::{self},
+    pathvalues::{path_matches, project},
+    stats,
+    text::{self, Analyzer, StandardAnalyzer},
 };
 
+// The query language is in parser.rs, along with Display for QP.
+pub use crate::parser::{parse, ParseError};
+use crate::parser::{DisplayPath, DisplayValue};
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum TaggableValue {
     Null,
     Bool(bool),
     String(String),
-    RcString(Rc<String>), // Rc<String> avoids cloning field name string buffers
+    ArcString(Arc<String>), // Arc<String> avoids cloning field name string buffers
     // ArrayIndex(usize), // Can we encode a usize more easily?
     Number(f64),
+    // AnyIndex is a path component that matches any index of an
+    // array, eg, keypath!["pet", TaggableValue::AnyIndex] matches
+    // documents where any element of the `pet` array matches. The
+    // index only has the forms of paths with every array index
+    // replaced, so a path can't have both AnyIndex and array indexes,
+    // eg, `orders[0].items[*]`; queries with one are invalid.
+    AnyIndex,
+}
+
+// JsonType is the type of a primitive JSON value.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum JsonType {
+    Null,
+    Bool,
+    Number,
+    String,
+}
+
+impl TaggableValue {
+    // Returns the JSON type of the value, or None for AnyIndex
+    // which is only meaningful in paths.
+    pub fn json_type(&self) -> Option<JsonType> {
+        match self {
+            TaggableValue::Null => Some(JsonType::Null),
+            TaggableValue::Bool(_) => Some(JsonType::Bool),
+            TaggableValue::String(_) | TaggableValue::ArcString(_) => Some(JsonType::String),
+            TaggableValue::Number(_) => Some(JsonType::Number),
+            TaggableValue::AnyIndex => None,
+        }
+    }
 }
 
 pub fn tv<T: Into<TaggableValue>>(v: T) -> TaggableValue {
@@ -58,9 +106,9 @@ impl From<String> for TaggableValue {
     }
 }
 
-impl From<Rc<String>> for TaggableValue {
-    fn from(value: Rc<String>) -> Self {
-        TaggableValue::RcString(value)
+impl From<Arc<String>> for TaggableValue {
+    fn from(value: Arc<String>) -> Self {
+        TaggableValue::ArcString(value)
     }
 }
 
@@ -72,13 +120,20 @@ impl From<Rc<String>> for TaggableValue {
 // But we can't have generics in the enum definition.
 
 // QP is a query predicate. A query is a list of
-// QPs that are ANDed together.
-#[derive(PartialOrd, PartialEq)]
+// QPs that are ANDed together. The And, Or and Not
+// variants allow predicates to be combined into
+// arbitrarily nested boolean expressions.
+#[derive(Debug, PartialOrd, PartialEq)]
 pub enum QP {
     E {
         p: Vec<TaggableValue>,
         v: TaggableValue,
     },
+    // In matches documents where the value at p equals any of vs.
+    In {
+        p: Vec<TaggableValue>,
+        vs: Vec<TaggableValue>,
+    },
     GT {
         p: Vec<TaggableValue>,
         v: TaggableValue,
@@ -95,27 +150,1030 @@ pub enum QP {
         p: Vec<TaggableValue>,
         v: TaggableValue,
     },
+    // Prefix matches documents with a string value at p
+    // that starts with prefix.
+    Prefix {
+        p: Vec<TaggableValue>,
+        prefix: String,
+    },
+    // Exists matches documents with any value at p, including
+    // documents where p is an object with at least one field or an
+    // array with at least one element. Empty objects and arrays have
+    // no index keys, so a document where p is {} or [] doesn't match.
+    Exists {
+        p: Vec<TaggableValue>,
+    },
+    // Type matches documents with a value of JSON type t at p.
+    Type {
+        p: Vec<TaggableValue>,
+        t: JsonType,
+    },
+    // Size matches documents with an array at p whose length
+    // compares to n using op. Empty arrays have a length of 0.
+    Size {
+        p: Vec<TaggableValue>,
+        op: Cmp,
+        n: usize,
+    },
+    // NE matches documents with a value at p that is not v. Documents
+    // without a value at p don't match; use Not(E) to include them.
+    // Nor do documents with an array or object at p, eg, `pet != "dog"`
+    // doesn't match {"pet": ["dog"]} or {"pet": ["cat"]}. For AnyIndex
+    // paths, NE matches if any element is not v.
+    NE {
+        p: Vec<TaggableValue>,
+        v: TaggableValue,
+    },
+    // NotIn matches documents with a value at p that is none of vs,
+    // with the same semantics as NE for missing fields and arrays.
+    NotIn {
+        p: Vec<TaggableValue>,
+        vs: Vec<TaggableValue>,
+    },
+    // Match matches documents whose full-text indexed text at p
+    // contains any of the terms in q. When a query contains Match,
+    // results are ordered by their BM25 score, highest first.
+    Match {
+        p: Vec<TaggableValue>,
+        q: String,
+    },
+    // ElemMatch matches documents where a single element of the array
+    // at p satisfies every predicate in qps. The paths in qps are
+    // relative to the array element, so keypath![] matches elements
+    // that are themselves values. Their paths can use AnyIndex for the
+    // elements of arrays within the element. qps can contain And, Or,
+    // Not and any leaf predicate apart from ElemMatch and Size.
+    ElemMatch {
+        p: Vec<TaggableValue>,
+        qps: Vec<QP>,
+    },
+    // The boolean operators are kept after the leaf predicates
+    // so sorting a conjunction runs the index scans first. Missing
+    // and Not are last, as within an AND they can be evaluated by
+    // removing IDs from the existing result set rather than against
+    // every document in the database.
+    And(Vec<QP>),
+    Or(Vec<QP>),
+    // Missing matches documents that have no value at p, including
+    // those where p is {} or []. It is the same as Not(Exists { p }).
+    Missing {
+        p: Vec<TaggableValue>,
+    },
+    Not(Box<QP>),
+}
+
+// Cmp is a comparison operator, for predicates that take one.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Cmp {
+    E,
+    GT,
+    GTE,
+    LT,
+    LTE,
 }
 
 pub type Query = Vec<QP>;
+#[derive(Clone, Debug, Default)]
 pub struct QueryStats {
     pub scans: u16,
+    // The number of index keys read by each scan
+    pub scan_keys: Vec<usize>,
+    // The size of the keys and values read
+    pub bytes_read: u64,
+    // The number of keys skipped as they couldn't be decoded, which
+    // suggests the database is corrupted.
+    pub decode_failures: u64,
+    // The number of documents read by search_documents to return them
+    pub docs_fetched: u64,
+    // The steps of the query's top-level AND that were evaluated,
+    // in order.
+    pub steps: Vec<StepStats>,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct StepStats {
+    // The predicate, or the predicates evaluated together, as in
+    // ExplainStep.
+    pub description: String,
+    // The number of IDs that could still be results after the step
+    pub ids: u64,
+    // The time taken to evaluate the step. Steps evaluated at once
+    // on separate threads overlap.
+    pub elapsed: Duration,
 }
+
+impl QueryStats {
+    // Counts a new scan, returning its index in scan_keys
+    pub(crate) fn start_scan(&mut self) -> usize {
+        self.scans += 1;
+        self.scan_keys.push(0);
+        self.scan_keys.len() - 1
+    }
+
+    // Counts a key and value read by a scan
+    pub(crate) fn read_key(&mut self, scan: usize, k: &[u8], v: &[u8]) {
+        self.scan_keys[scan] += 1;
+        self.bytes_read += (k.len() + v.len()) as u64;
+    }
+
+    // Adds the stats of part of the query evaluated separately
+    fn add(&mut self, other: QueryStats) {
+        self.scans += other.scans;
+        self.scan_keys.extend(other.scan_keys);
+        self.bytes_read += other.bytes_read;
+        self.decode_failures += other.decode_failures;
+        self.docs_fetched += other.docs_fetched;
+        self.steps.extend(other.steps);
+    }
+}
+
 pub struct QueryResult {
     pub results: Vec<String>,
     pub stats: QueryStats,
+    // When a limit cut the results short, a continuation token to pass
+    // as QueryOptions.after to get the next page.
+    pub next: Option<String>,
+}
+
+// QueryOptions alter how search_index_with_options evaluates a query.
+#[derive(Default)]
+pub struct QueryOptions {
+    // By default, range predicates follow the index ordering across
+    // JSON types, so, eg, `age < "a"` matches every numeric age.
+    // Setting strict_types limits GT, GTE, LT and LTE predicates to
+    // values of the same JSON type as the predicate's value.
+    pub strict_types: bool,
+    // The analyzer used to split the text of Match predicates into
+    // terms. It should be the one the text was indexed with. Defaults
+    // to the StandardAnalyzer.
+    pub analyzer: Option<Box<dyn Analyzer>>,
+    // When set, results are sorted by their value at a path rather
+    // than by document ID, or score for Match queries.
+    pub order_by: Option<OrderBy>,
+    // Return at most limit results, after skipping the first skip.
+    pub limit: Option<usize>,
+    pub skip: usize,
+    // A continuation token from QueryResult.next, to return the results
+    // after the previous page. Pages are positioned by the last result's
+    // ID or index key rather than a count, so documents written between
+    // pages don't cause results to be repeated or missed.
+    pub after: Option<String>,
+    // The paths search_documents returns from each document, rather
+    // than the whole document.
+    pub fields: Option<Vec<Vec<TaggableValue>>>,
+    // The number of threads used to evaluate the independent parts of
+    // a query, such as the predicates of an AND or OR, at once. With 0
+    // or 1 they are evaluated in turn on the calling thread. An AND's
+    // predicates are evaluated this many at a time, so that if any
+    // have no results the rest aren't evaluated. Only the outermost
+    // parts are evaluated at once; those nested within them are
+    // evaluated in turn on their part's thread.
+    pub parallelism: usize,
+    // Limits on the work a query does. A query reaching one stops, and
+    // returns a LimitExceeded error with the stats of its work so far.
+    // The number of index keys read, across all its scans
+    pub max_keys: Option<usize>,
+    // The number of document IDs held by any one step of the query,
+    // such as a scan or the union of an OR's predicates.
+    pub max_ids: Option<u64>,
+    pub deadline: Option<Instant>,
+    // Cancels the query when cancelled, eg, from another thread
+    pub cancel: Option<CancelHandle>,
+    // Called by search_index_with_options and search_documents_with_options
+    // with the query, in the query language, and its stats once it's
+    // evaluated, eg, to log slow queries. It's also called when the
+    // query stops at a limit.
+    pub on_stats: Option<Box<StatsCallback>>,
+}
+
+pub type StatsCallback = dyn Fn(&str, &QueryStats) + Send + Sync;
+
+// Limit is the QueryOptions limit that stopped a query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Keys,
+    Ids,
+    Deadline,
+    Cancelled,
+}
+
+// CancelHandle cancels the queries it's passed to in QueryOptions.
+// Clones share the same state, so one can be kept to cancel a query
+// running on another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+}
+
+// OrderBy sorts results by their value at p, in the index's order
+// of values. Documents with no value at p come last, in ID order,
+// whatever the direction. For AnyIndex paths a document is ordered by
+// its lowest element when ascending and highest when descending.
+pub struct OrderBy {
+    pub p: Vec<TaggableValue>,
+    pub direction: Direction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Asc,
+    Desc,
 }
 
-pub fn search_index(db: &Db, mut q: Query) -> Result<QueryResult, DocDbError> {
+pub fn search_index(db: &Db, q: Query) -> Result<QueryResult, DocDbError> {
+    search_index_with_options(db, q, &QueryOptions::default())
+}
+
+pub fn search_index_with_options(
+    db: &Db,
+    q: Query,
+    opts: &QueryOptions,
+) -> Result<QueryResult, DocDbError> {
+    search(db, q, opts, |_, _| Ok(())).map(|(r, _)| r)
+}
+
+// Evaluates q, then calls fetch with the page of results, so that the
+// stats opts.on_stats is called with include those of fetching them.
+fn search<T>(
+    db: &Db,
+    q: Query,
+    opts: &QueryOptions,
+    fetch: impl FnOnce(&mut QueryStats, &[String]) -> Result<T, DocDbError>,
+) -> Result<(QueryResult, T), DocDbError> {
     // I think Query here is a one-time use thing, so we should own it. Db
     // will be used again and again, so we should borrow it.
+    let start = Instant::now();
+    let limits = Limits::new(opts);
+    let mut ctx = Ctx {
+        db,
+        opts,
+        limits: &limits,
+        parallelism: opts.parallelism.max(1),
+        stats: QueryStats::default(),
+        scores: BTreeMap::new(),
+        scans: None,
+    };
+
+    let after = opts.after.as_deref().map(Cursor::decode).transpose()?;
+    // One more than needed, so we know whether there's another page
+    let want = opts.limit.map(|limit| opts.skip + limit + 1);
+
+    let description = opts.on_stats.as_ref().map(|_| {
+        let qps: Vec<String> = q.iter().map(|qp| qp.to_string()).collect();
+        qps.join(" AND ")
+    });
+    let page = eval_page(&mut ctx, q, after.as_ref(), want).and_then(|page| {
+        let mut page: Vec<(String, Cursor)> = page.into_iter().skip(opts.skip).collect();
+        let next = match opts.limit {
+            Some(limit) if page.len() > limit => {
+                page.truncate(limit);
+                page.last().map(|(_, c)| c.encode())
+            }
+            _ => None,
+        };
+        let results: Vec<String> = page.into_iter().map(|(id, _)| id).collect();
+        let fetched = fetch(&mut ctx.stats, &results)?;
+        Ok((results, next, fetched))
+    });
+    ctx.stats.elapsed = start.elapsed();
+    if let (Some(on_stats), Some(description)) = (&opts.on_stats, description) {
+        on_stats(&description, &ctx.stats);
+    }
+    let (results, next, fetched) = page.map_err(|e| ctx.with_stats(e))?;
+    let r = QueryResult {
+        results,
+        stats: ctx.stats,
+        next,
+    };
+    Ok((r, fetched))
+}
+
+// DocumentResult is a QueryResult with the documents of the results.
+pub struct DocumentResult {
+    pub results: Vec<(String, Value)>,
+    pub stats: QueryStats,
+    pub next: Option<String>,
+}
 
-    // BTreeMap so we return IDs to caller in order
-    let mut result_ids = BTreeMap::new();
-    let mut n_preds = 0;
-    let mut stats = QueryStats { scans: 0 };
-    let mut first_predicate = true;
+pub fn search_documents(db: &Db, q: Query) -> Result<DocumentResult, DocDbError> {
+    search_documents_with_options(db, q, &QueryOptions::default())
+}
+
+// Searches the index, then returns each result's document, projected
+// to opts.fields if it's set. Documents deleted since the search
+// are left out.
+pub fn search_documents_with_options(
+    db: &Db,
+    q: Query,
+    opts: &QueryOptions,
+) -> Result<DocumentResult, DocDbError> {
+    let fields: Option<Vec<&[TaggableValue]>> = opts
+        .fields
+        .as_ref()
+        .map(|fields| fields.iter().map(|p| p.as_slice()).collect());
+    let (r, results) = search(db, q, opts, |stats, ids| {
+        let mut results = vec![];
+        for id in ids {
+            stats.docs_fetched += 1;
+            let doc = match docdb::get_document(db, id)? {
+                Some(doc) => doc,
+                None => continue,
+            };
+            let doc = match &fields {
+                Some(fields) => project(doc, fields).unwrap_or_else(|| json!({})),
+                None => doc,
+            };
+            results.push((id.clone(), doc));
+        }
+        Ok(results)
+    })?;
+    Ok(DocumentResult {
+        results,
+        stats: r.stats,
+        next: r.next,
+    })
+}
 
+fn eval_page(
+    ctx: &mut Ctx,
+    q: Query,
+    after: Option<&Cursor>,
+    want: Option<usize>,
+) -> Result<Vec<(String, Cursor)>, DocDbError> {
+    check_paths(&q)?;
+    match &ctx.opts.order_by {
+        Some(order_by) => {
+            check_path(&order_by.p)?;
+            eval_ordered(ctx, q, order_by, after, want)
+        }
+        None => {
+            // BTreeSet so we return IDs to caller in order
+            let result_ids = docids(ctx.db, &eval_query(ctx, q, None)?)?;
+            page_by_id(ctx, result_ids, after, want)
+        }
+    }
+}
+
+// Explain describes how a query was evaluated, for working out why
+// it's slow. It's returned by explain.
+pub struct Explain {
+    // The query's predicates, in the query language, in the order
+    // they're evaluated.
+    pub order: Vec<String>,
+    // Range predicates on the same field are collapsed into a
+    // single step, which scans the overlap of their ranges.
+    pub steps: Vec<ExplainStep>,
+    // Whether evaluation stopped before the last step because the
+    // query can't have any results.
+    pub short_circuited: bool,
+    pub results: usize,
+    pub stats: QueryStats,
+}
+
+pub struct ExplainStep {
+    // The predicate, or the field of collapsed range predicates
+    pub description: String,
+    // The index scans of the step, including those of predicates
+    // within it, eg, in an Or.
+    pub scans: Vec<ExplainScan>,
+    // The number of IDs that could still be results after the step,
+    // or None if the step wasn't evaluated.
+    pub ids: Option<usize>,
+}
+
+pub struct ExplainScan {
+    pub p: Vec<TaggableValue>,
+    // Whether the scan is of array sizes rather than values
+    pub array_sizes: bool,
+    // Where the scan starts and ends among the values at p,
+    // eg, `after 10` or `before "john"`.
+    pub start: String,
+    pub end: String,
+    // The number of keys read by the scan
+    pub keys: usize,
+}
+
+impl ExplainStep {
+    // The number of keys read by the step's scans
+    pub fn keys(&self) -> usize {
+        self.scans.iter().map(|s| s.keys).sum()
+    }
+}
+
+// Evaluates q as search_index_with_options would, and returns how: the
+// order of its predicates, the index scans made for each and how many
+// IDs are left after each. Ordering and paging options are ignored.
+pub fn explain(db: &Db, q: Query, opts: &QueryOptions) -> Result<Explain, DocDbError> {
+    let start = Instant::now();
+    let limits = Limits::new(opts);
+    let mut ctx = Ctx {
+        db,
+        opts,
+        limits: &limits,
+        parallelism: opts.parallelism.max(1),
+        stats: QueryStats::default(),
+        scores: BTreeMap::new(),
+        scans: Some(vec![]),
+    };
+    let mut explain = Explain {
+        order: vec![],
+        steps: vec![],
+        short_circuited: false,
+        results: 0,
+        stats: QueryStats::default(),
+    };
+    check_paths(&q)?;
+    let ids = eval_query(&mut ctx, q, Some(&mut explain)).map_err(|e| ctx.with_stats(e))?;
+    explain.results = ids.len() as usize;
+    explain.stats = ctx.stats;
+    explain.stats.elapsed = start.elapsed();
+    Ok(explain)
+}
+
+// Describes a scan's start or end key in terms of the values at p.
+fn describe_bound(p: &Vec<TaggableValue>, k: &[u8]) -> String {
+    use encoding::QueryBound;
+    let type_name = |t: &JsonType| match t {
+        JsonType::Null => "null",
+        JsonType::Bool => "bool",
+        JsonType::Number => "number",
+        JsonType::String => "string",
+    };
+    match encoding::decode_query_bound(p, k) {
+        Some(QueryBound::Start) => "start".to_string(),
+        Some(QueryBound::End) => "end".to_string(),
+        Some(QueryBound::Before(v)) => format!("before {}", DisplayValue(&v)),
+        Some(QueryBound::After(v)) => format!("after {}", DisplayValue(&v)),
+        Some(QueryBound::BeforeType(t)) => format!("before {} values", type_name(&t)),
+        Some(QueryBound::AfterType(t)) => format!("after {} values", type_name(&t)),
+        Some(QueryBound::AfterPrefix(s)) => {
+            format!("after prefix {}", DisplayValue(&TaggableValue::from(s)))
+        }
+        None => format!("{:?}", k),
+    }
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "order: {}", self.order.join(", "))?;
+        for (i, step) in self.steps.iter().enumerate() {
+            match step.ids {
+                Some(ids) => writeln!(f, "{}. {}: {} ids", i + 1, step.description, ids)?,
+                None => writeln!(f, "{}. {}: not evaluated", i + 1, step.description)?,
+            }
+            for scan in &step.scans {
+                let sizes = if scan.array_sizes { " SIZE" } else { "" };
+                writeln!(
+                    f,
+                    "   scan {}{} from {} to {}: {} keys",
+                    DisplayPath(&scan.p),
+                    sizes,
+                    scan.start,
+                    scan.end,
+                    scan.keys
+                )?;
+            }
+        }
+        if self.short_circuited {
+            writeln!(f, "short-circuited")?;
+        }
+        write!(f, "results: {}, scans: {}", self.results, self.stats.scans)
+    }
+}
+
+// Cursor is the position of a result in the results, used to
+// continue from it. It's given to callers as an opaque token.
+#[derive(Debug, PartialEq)]
+enum Cursor {
+    // The document ID, for results in ID order
+    Id(String),
+    // The score and ID, for results ranked by score
+    Score(f64, String),
+    // The index key, for results sorted by order by
+    Key(Vec<u8>),
+    // The ID, for sorted results without a value at the order by path
+    Missing(String),
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let mut b = vec![];
+        match self {
+            Cursor::Id(id) => {
+                b.push(b'i');
+                b.extend(id.as_bytes());
+            }
+            Cursor::Score(score, id) => {
+                b.push(b's');
+                b.extend(score.to_be_bytes());
+                b.extend(id.as_bytes());
+            }
+            Cursor::Key(k) => {
+                b.push(b'k');
+                b.extend(k);
+            }
+            Cursor::Missing(id) => {
+                b.push(b'm');
+                b.extend(id.as_bytes());
+            }
+        }
+        b.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    fn decode(token: &str) -> Result<Cursor, DocDbError> {
+        let invalid = || DocDbError::InvalidQuery("invalid continuation token".to_string());
+        if !token.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let b = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let id = |b: &[u8]| String::from_utf8(b.to_vec()).map_err(|_| invalid());
+        match b.split_first() {
+            Some((b'i', rest)) => Ok(Cursor::Id(id(rest)?)),
+            Some((b's', rest)) if rest.len() >= 8 => {
+                let (score, rest) = rest.split_at(8);
+                let score = f64::from_be_bytes(score.try_into().map_err(|_| invalid())?);
+                Ok(Cursor::Score(score, id(rest)?))
+            }
+            Some((b'k', rest)) => Ok(Cursor::Key(rest.to_vec())),
+            Some((b'm', rest)) => Ok(Cursor::Missing(id(rest)?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn wrong_cursor() -> DocDbError {
+    DocDbError::InvalidQuery("continuation token is not for this query's ordering".to_string())
+}
+
+// Returns the results after the cursor, up to want of them, in ID
+// order or, when the query has a Match, by score.
+fn page_by_id(
+    ctx: &Ctx,
+    ids: BTreeSet<String>,
+    after: Option<&Cursor>,
+    want: Option<usize>,
+) -> Result<Vec<(String, Cursor)>, DocDbError> {
+    let want = want.unwrap_or(usize::MAX);
+    if ctx.scores.is_empty() {
+        let start = match after {
+            Some(Cursor::Id(id)) => Bound::Excluded(id.clone()),
+            None => Bound::Unbounded,
+            Some(_) => return Err(wrong_cursor()),
+        };
+        return Ok(ids
+            .range((start, Bound::Unbounded))
+            .take(want)
+            .map(|id| (id.clone(), Cursor::Id(id.clone())))
+            .collect());
+    }
+
+    // Full-text matches are ranked by score instead. The sort is
+    // stable, so IDs with the same score stay in order.
+    let score = |id: &String| ctx.scores.get(id).copied().unwrap_or(0.0);
+    let mut ids: Vec<(f64, String)> = ids.into_iter().map(|id| (score(&id), id)).collect();
+    ids.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let is_after = |s: f64, id: &String| match after {
+        Some(Cursor::Score(after_s, after_id)) => {
+            s.total_cmp(after_s).is_lt() || (s.total_cmp(after_s).is_eq() && id > after_id)
+        }
+        _ => true,
+    };
+    if after.is_some_and(|c| !matches!(c, Cursor::Score(..))) {
+        return Err(wrong_cursor());
+    }
+    Ok(ids
+        .into_iter()
+        .filter(|(s, id)| is_after(*s, id))
+        .take(want)
+        .map(|(s, id)| (id.clone(), Cursor::Score(s, id)))
+        .collect())
+}
+
+// Ctx holds the state used while evaluating a single query.
+pub(crate) struct Ctx<'a> {
+    pub(crate) db: &'a Db,
+    opts: &'a QueryOptions,
+    limits: &'a Limits<'a>,
+    // The number of steps evaluated at once. It's 1 in forked Ctxs, so
+    // the steps nested in a forked step are evaluated in turn, and a
+    // query uses at most opts.parallelism threads.
+    parallelism: usize,
+    pub(crate) stats: QueryStats,
+    // BM25 scores of the documents matched by Match predicates
+    scores: BTreeMap<String, f64>,
+    // When explaining a query, the index scans made since
+    // the last step of the query was recorded.
+    scans: Option<Vec<ExplainScan>>,
+}
+
+impl<'a> Ctx<'a> {
+    // A Ctx for evaluating part of the query on another thread
+    fn fork(&self) -> Ctx<'a> {
+        Ctx {
+            db: self.db,
+            opts: self.opts,
+            limits: self.limits,
+            parallelism: 1,
+            stats: QueryStats::default(),
+            scores: BTreeMap::new(),
+            scans: self.scans.as_ref().map(|_| vec![]),
+        }
+    }
+
+    // Adds the stats and scores of a forked Ctx to this one,
+    // and returns its scans.
+    fn join(&mut self, forked: Ctx) -> Vec<ExplainScan> {
+        self.stats.add(forked.stats);
+        for (id, score) in forked.scores {
+            *self.scores.entry(id).or_insert(0.0) += score;
+        }
+        forked.scans.unwrap_or_default()
+    }
+
+    // Takes the scans recorded after the first n
+    fn scans_since(&mut self, n: usize) -> Vec<ExplainScan> {
+        self.scans
+            .as_mut()
+            .map(|scans| scans.split_off(n))
+            .unwrap_or_default()
+    }
+
+    fn add_scans(&mut self, scans: Vec<ExplainScan>) {
+        if let Some(s) = self.scans.as_mut() {
+            s.extend(scans);
+        }
+    }
+
+    // Counts a key and value read by a scan, returning an error if
+    // the query should stop.
+    pub(crate) fn read_key(&mut self, scan: usize, k: &[u8], v: &[u8]) -> Result<(), DocDbError> {
+        self.stats.read_key(scan, k, v);
+        self.limits.read_key()
+    }
+
+    // Adds the stats of the query so far to a LimitExceeded error,
+    // which is created where the limit is reached without them.
+    fn with_stats(&mut self, e: DocDbError) -> DocDbError {
+        match e {
+            DocDbError::LimitExceeded(limit, _) => {
+                DocDbError::LimitExceeded(limit, self.stats.clone())
+            }
+            e => e,
+        }
+    }
+}
+
+// Limits checks the work done by a query against the limits in its
+// QueryOptions. It's shared by the threads evaluating the query.
+struct Limits<'a> {
+    opts: &'a QueryOptions,
+    keys: AtomicUsize,
+}
+
+// How many keys to read between checks of the deadline, as reading
+// the clock is slower than reading a key.
+const DEADLINE_CHECK_KEYS: usize = 256;
+
+impl<'a> Limits<'a> {
+    fn new(opts: &'a QueryOptions) -> Self {
+        Limits {
+            opts,
+            keys: AtomicUsize::new(0),
+        }
+    }
+
+    // Counts an index key read, returning an error if the query
+    // should stop.
+    fn read_key(&self) -> Result<(), DocDbError> {
+        let keys = self.keys.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        if self.opts.max_keys.is_some_and(|max| keys > max) {
+            return Err(limit_exceeded(Limit::Keys));
+        }
+        if self.opts.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return Err(limit_exceeded(Limit::Cancelled));
+        }
+        if keys.is_multiple_of(DEADLINE_CHECK_KEYS) {
+            self.check_deadline()?;
+        }
+        Ok(())
+    }
+
+    // Returns an error if a step of the query holding n IDs should stop
+    fn hold_ids(&self, n: u64) -> Result<(), DocDbError> {
+        if self.opts.max_ids.is_some_and(|max| n > max) {
+            return Err(limit_exceeded(Limit::Ids));
+        }
+        self.check_deadline()
+    }
+
+    fn check_deadline(&self) -> Result<(), DocDbError> {
+        match self.opts.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(limit_exceeded(Limit::Deadline)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Returns an InvalidQuery error if p has both AnyIndex and array
+// indexes, as no index keys are for such paths.
+pub(crate) fn check_path(p: &[TaggableValue]) -> Result<(), DocDbError> {
+    if p.contains(&TaggableValue::AnyIndex)
+        && p.iter().any(|c| matches!(c, TaggableValue::Number(_)))
+    {
+        return Err(DocDbError::InvalidQuery(format!(
+            "path {} cannot have both array indexes and [*]",
+            DisplayPath(p)
+        )));
+    }
+    Ok(())
+}
+
+// Checks the paths of every predicate in q. The paths within an
+// ElemMatch are relative to an element, so aren't index paths.
+fn check_paths(q: &[QP]) -> Result<(), DocDbError> {
+    for qp in q {
+        match qp {
+            QP::And(qps) | QP::Or(qps) => check_paths(qps)?,
+            QP::Not(qp) => check_paths(std::slice::from_ref(&**qp))?,
+            QP::E { p, .. }
+            | QP::In { p, .. }
+            | QP::GT { p, .. }
+            | QP::GTE { p, .. }
+            | QP::LT { p, .. }
+            | QP::LTE { p, .. }
+            | QP::Prefix { p, .. }
+            | QP::Exists { p }
+            | QP::Type { p, .. }
+            | QP::Size { p, .. }
+            | QP::NE { p, .. }
+            | QP::NotIn { p, .. }
+            | QP::Match { p, .. }
+            | QP::ElemMatch { p, .. }
+            | QP::Missing { p } => check_path(p)?,
+        }
+    }
+    Ok(())
+}
+
+// The stats are added by Ctx::with_stats on the way out
+fn limit_exceeded(limit: Limit) -> DocDbError {
+    DocDbError::LimitExceeded(limit, QueryStats::default())
+}
+
+// Evaluate a single predicate, recursing into boolean
+// operators, and return the ordinals of the matching documents.
+fn eval(ctx: &mut Ctx, qp: QP) -> Result<RoaringTreemap, DocDbError> {
+    match qp {
+        QP::And(qps) => eval_and(ctx, qps),
+        QP::Or(qps) => eval_or(ctx, qps),
+        QP::Missing { p } => eval(ctx, QP::Not(Box::new(QP::Exists { p }))),
+        QP::In { p, vs } => eval_ranges(ctx, &p, eq_ranges(&p, &vs)),
+        QP::NE { p, v } => eval_ranges(ctx, &p, complement_ranges(&p, eq_ranges(&p, &[v]))),
+        QP::NotIn { p, vs } => eval_ranges(ctx, &p, complement_ranges(&p, eq_ranges(&p, &vs))),
+        QP::Match { p, q } => eval_match(ctx, p, q),
+        QP::ElemMatch { p, qps } => eval_elem_match(ctx, p, qps),
+        QP::Not(qp) => {
+            // Without an existing result set to remove IDs from,
+            // NOT has to be evaluated against every document.
+            let all_ids = all_ordinals(ctx)?;
+            Ok(all_ids - eval(ctx, *qp)?)
+        }
+        qp => match range_keys(&qp, ctx.opts.strict_types) {
+            Some((p, start_key, end_key)) => eval_step(
+                ctx,
+                Step::Scan {
+                    p: p.clone(),
+                    start_key,
+                    end_key,
+                    exact: is_exact(&qp),
+                },
+            ),
+            None => unreachable!("all leaf predicates are range scans"),
+        },
+    }
+}
+
+// Evaluates q and returns the IDs ordered by their value at the
+// order by path, which we get by scanning the index for the path, as
+// it's already in that order. Range predicates on the path narrow the
+// scan, and if they are the whole query the scan alone is enough.
+// Returns up to want results after the cursor, and stops scanning
+// once it has them.
+fn eval_ordered(
+    ctx: &mut Ctx,
+    q: Query,
+    order_by: &OrderBy,
+    after: Option<&Cursor>,
+    want: Option<usize>,
+) -> Result<Vec<(String, Cursor)>, DocDbError> {
+    let p = &order_by.p;
+    let field = encoding::encode_path(p);
+
+    // Intersect the ranges of the predicates on p, as collapse_ranges
+    // does, while every predicate in the query is one of them.
+    let mut covered = !p.contains(&TaggableValue::AnyIndex);
+    let mut exact = false;
+    let mut range: Option<(Vec<u8>, Vec<u8>)> = None;
+    for qp in &q {
+        if !covered {
+            break;
+        }
+        match range_keys(qp, ctx.opts.strict_types) {
+            Some((qp_p, s, e))
+                if !matches!(qp, QP::Size { .. }) && encoding::encode_path(qp_p) == field =>
+            {
+                exact |= is_exact(qp);
+                range = Some(match range {
+                    Some((rs, re)) => (rs.max(s), re.min(e)),
+                    None => (s, e),
+                });
+            }
+            _ => covered = false,
+        }
+    }
+    let (start_key, end_key, ids) = match range {
+        Some((s, e)) if covered => (s, e, None),
+        // Only the values at p, not those of fields below p
+        _ => (
+            encoding::query_type_lower_bound(p, JsonType::Null),
+            encoding::query_type_upper_bound(p, JsonType::String),
+            Some(docids(ctx.db, &eval_query(ctx, q, None)?)?),
+        ),
+    };
+    if start_key >= end_key || ids.as_ref().is_some_and(|ids| ids.is_empty()) {
+        return Ok(vec![]);
+    }
+    let asc = order_by.direction == Direction::Asc;
+
+    // When the scan is for the predicates, documents matching a field
+    // below p are results without a value at p, as they would be
+    // matched by the predicate scans, unless the scan is exact.
+    let mut below = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    let mut results = vec![];
+    let want = want.unwrap_or(usize::MAX);
+    let is_result = |id: &String| ids.as_ref().is_none_or(|ids| ids.contains(id));
+
+    // With one value per document, a page can start scanning at the
+    // cursor. AnyIndex paths are scanned from the start, so documents
+    // aren't returned again for a later element.
+    let single = !p.contains(&TaggableValue::AnyIndex);
+    let (page_start, page_end) = match after {
+        None => (start_key.clone(), end_key.clone()),
+        Some(Cursor::Key(k)) if single && asc => {
+            let mut successor = k.clone();
+            successor.push(0x00);
+            (successor.max(start_key.clone()), end_key.clone())
+        }
+        Some(Cursor::Key(k)) if single => (start_key.clone(), k.clone().min(end_key.clone())),
+        Some(Cursor::Key(_)) => (start_key.clone(), end_key.clone()),
+        // Every document with a value at p has been returned
+        Some(Cursor::Missing(_)) => (end_key.clone(), end_key.clone()),
+        Some(_) => return Err(wrong_cursor()),
+    };
+    if page_start < page_end {
+        scan_ordered(
+            ctx,
+            &page_start,
+            &page_end,
+            asc,
+            &field,
+            &mut below,
+            |k, id| {
+                if !is_result(&id) || !seen.insert(id.clone()) {
+                    return true;
+                }
+                let is_after = match after {
+                    Some(Cursor::Key(after)) if asc => k > after.as_slice(),
+                    Some(Cursor::Key(after)) => k < after.as_slice(),
+                    _ => true,
+                };
+                if is_after {
+                    results.push((id, Cursor::Key(k.to_vec())));
+                }
+                results.len() < want
+            },
+        )?;
+        if results.len() >= want {
+            return Ok(results);
+        }
+    }
+
+    // The documents without a value at p come next. To know which they
+    // are we need all the documents with a value at p, so scan any of
+    // the range that this page skipped.
+    if (page_start, page_end) != (start_key.clone(), end_key.clone()) {
+        seen.clear();
+        scan_ordered(
+            ctx,
+            &start_key,
+            &end_key,
+            asc,
+            &field,
+            &mut below,
+            |_, id| {
+                if is_result(&id) {
+                    seen.insert(id);
+                }
+                true
+            },
+        )?;
+    }
+    let missing_after = match after {
+        Some(Cursor::Missing(id)) => Bound::Excluded(id.clone()),
+        _ => Bound::Unbounded,
+    };
+    if exact {
+        below.clear();
+    }
+    let missing = ids.as_ref().unwrap_or(&below);
+    results.extend(
+        missing
+            .range((missing_after, Bound::Unbounded))
+            .filter(|id| !seen.contains(*id))
+            .take(want - results.len())
+            .map(|id| (id.clone(), Cursor::Missing(id.clone()))),
+    );
+    Ok(results)
+}
+
+// Scans the index for field between start_key and end_key in order,
+// calling f with the key and ID of each value at field until it
+// returns false. IDs with values for fields below field go in below.
+fn scan_ordered(
+    ctx: &mut Ctx,
+    start_key: &[u8],
+    end_key: &[u8],
+    asc: bool,
+    field: &[u8],
+    below: &mut BTreeSet<String>,
+    mut f: impl FnMut(&[u8], String) -> bool,
+) -> Result<(), DocDbError> {
+    let scan = ctx.stats.start_scan();
+    let range = ctx.db.range(start_key..end_key);
+    let keys: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> = match asc {
+        true => Box::new(range),
+        false => Box::new(range.rev()),
+    };
+    for i in keys {
+        let (k, v) = i?;
+        ctx.read_key(scan, &k, &v)?;
+        let (path, _, docid) = match encoding::decode_index_key(&k) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                ctx.stats.decode_failures += 1;
+                continue;
+            }
+        };
+        if encoding::encode_path(&path) != field {
+            below.insert(docid);
+            continue;
+        }
+        if !f(&k, docid) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn eval_and(ctx: &mut Ctx, qps: Vec<QP>) -> Result<RoaringTreemap, DocDbError> {
+    eval_conjunction(ctx, qps, false, None)
+}
+
+// Evaluates the top-level conjunction of a query, recording the stats
+// of its steps and, if explain is given, its predicate order and steps
+// there.
+fn eval_query(
+    ctx: &mut Ctx,
+    q: Query,
+    explain: Option<&mut Explain>,
+) -> Result<RoaringTreemap, DocDbError> {
+    eval_conjunction(ctx, q, true, explain)
+}
+
+fn eval_conjunction(
+    ctx: &mut Ctx,
+    mut qps: Vec<QP>,
+    top_level: bool,
+    mut explain: Option<&mut Explain>,
+) -> Result<RoaringTreemap, DocDbError> {
     // Sort by the ordering in the enum, which puts equality
     // first, which is likely to have a smaller result set
     // than any range query. This means we likely end up using
@@ -123,137 +1181,1102 @@ pub fn search_index(db: &Db, mut q: Query) -> Result<QueryResult, DocDbError> {
     // are returned by the first predicate. Not sure if this sorts by the
     // path and value, as TaggableValue doesn't implement Ord.
     // https://stackoverflow.com/a/70588789
-    q.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    qps.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
 
-    for qp in q {
-        n_preds += 1;
-        let ids = match qp {
-            QP::E { p, v } => lookup_eq(db, p, v)?,
-            QP::GT { p, v } => lookup_gt(db, p, v)?,
-            QP::GTE { p, v } => lookup_gte(db, p, v)?,
-            QP::LT { p, v } => lookup_lt(db, p, v)?,
-            QP::LTE { p, v } => lookup_lte(db, p, v)?,
+    // Then order by the estimated number of results, so that a selective
+    // predicate goes first whatever its variant. The sort is stable, so
+    // predicates with the same estimate keep the order above.
+    if qps.len() > 1 {
+        let mut estimated = vec![];
+        for qp in qps {
+            estimated.push((stats::estimate(ctx.db, &qp)?, qp));
+        }
+        estimated.sort_by_key(|(n, _)| *n);
+        qps = estimated.into_iter().map(|(_, qp)| qp).collect();
+    }
+    if let Some(e) = explain.as_deref_mut() {
+        e.order = qps.iter().map(|qp| qp.to_string()).collect();
+    }
+
+    // Collapse range predicates on the same field into a single scan,
+    // or give up early if the field's ranges cannot overlap.
+    let steps = match collapse_ranges(qps, ctx.opts.strict_types) {
+        Some(steps) => steps,
+        None => {
+            if let Some(e) = explain {
+                e.short_circuited = true;
+            }
+            return Ok(RoaringTreemap::new());
+        }
+    };
+    let steps = merge_eqs(steps);
+    let descriptions: Vec<String> = match top_level {
+        true => steps.iter().map(describe_step).collect(),
+        false => vec![],
+    };
+    if let Some(e) = explain.as_deref_mut() {
+        e.steps = descriptions
+            .iter()
+            .map(|description| ExplainStep {
+                description: description.clone(),
+                scans: vec![],
+                ids: None,
+            })
+            .collect();
+    }
+
+    // As no result ID that appears in a later predicate but not the
+    // first predicate can be in the final result set, we only hold
+    // the IDs from the first predicate and narrow them down from there.
+    let mut result_ids: Option<RoaringTreemap> = None;
+
+    let is_not = |step: &Step| matches!(step, Step::Eval(QP::Not(_)));
+    let mut steps = steps
+        .into_iter()
+        .map(|step| match step {
+            Step::Eval(QP::Missing { p }) => Step::Eval(QP::Not(Box::new(QP::Exists { p }))),
+            step => step,
+        })
+        .peekable();
+    let mut i = 0;
+    while let Some(step) = steps.next() {
+        // Once we have IDs, a Not only needs to remove the IDs matching
+        // its predicate from them. Other steps are evaluated up to
+        // parallelism at a time.
+        let (negate, mut batch) = match step {
+            Step::Eval(QP::Not(qp)) if result_ids.is_some() => (true, vec![Step::Eval(*qp)]),
+            step => (false, vec![step]),
         };
-        stats.scans += 1;
+        while !negate && batch.len() < ctx.parallelism {
+            match steps.next_if(|step| !is_not(step)) {
+                Some(step) => batch.push(step),
+                None => break,
+            }
+        }
+
+        for r in eval_steps(ctx, batch)? {
+            let ids = match result_ids.take() {
+                Some(candidates) if negate => candidates - r.ids,
+                Some(candidates) => candidates & r.ids,
+                None => r.ids,
+            };
+            if top_level {
+                ctx.stats.steps.push(StepStats {
+                    description: descriptions[i].clone(),
+                    ids: ids.len(),
+                    elapsed: r.elapsed,
+                });
+            }
+            match explain.as_deref_mut() {
+                Some(e) => {
+                    e.steps[i].scans = r.scans;
+                    e.steps[i].ids = Some(ids.len() as usize);
+                }
+                None => ctx.add_scans(r.scans),
+            }
+            i += 1;
+            result_ids = Some(ids);
+        }
 
-        if ids.is_empty() {
+        if result_ids.as_ref().is_some_and(|ids| ids.is_empty()) {
             // Short-circuit evaluation; an empty result set means
             // this conjunction can't have any results. Stop scanning.
-            return Ok(QueryResult {
-                results: vec![],
-                stats,
+            if let Some(e) = explain {
+                e.short_circuited = steps.peek().is_some();
+            }
+            return Ok(RoaringTreemap::new());
+        }
+    }
+
+    Ok(result_ids.unwrap_or_default())
+}
+
+// The result of evaluating a step with eval_steps
+struct StepResult {
+    ids: RoaringTreemap,
+    // The scans made, when explaining the query
+    scans: Vec<ExplainScan>,
+    elapsed: Duration,
+}
+
+// Evaluates steps, up to ctx.parallelism of them at once on their own
+// threads, and returns their results in order.
+fn eval_steps(ctx: &mut Ctx, steps: Vec<Step>) -> Result<Vec<StepResult>, DocDbError> {
+    let timed = |ctx: &mut Ctx, step| {
+        let start = Instant::now();
+        eval_step(ctx, step).map(|ids| (ids, start.elapsed()))
+    };
+    let mut results = vec![];
+    let mut steps = steps.into_iter().peekable();
+    while steps.peek().is_some() {
+        let batch: Vec<Step> = steps.by_ref().take(ctx.parallelism).collect();
+        if batch.len() == 1 {
+            for step in batch {
+                let n_scans = ctx.scans.as_ref().map_or(0, Vec::len);
+                let (ids, elapsed) = timed(ctx, step)?;
+                let scans = ctx.scans_since(n_scans);
+                results.push(StepResult {
+                    ids,
+                    scans,
+                    elapsed,
+                });
+            }
+            continue;
+        }
+        let done = std::thread::scope(|s| {
+            let threads: Vec<_> = batch
+                .into_iter()
+                .map(|step| {
+                    let mut ctx = ctx.fork();
+                    s.spawn(move || {
+                        #[cfg(test)]
+                        let _worker = tests::Worker::start();
+                        (timed(&mut ctx, step), ctx)
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect::<Vec<_>>()
+        });
+        for (r, forked) in done {
+            let scans = ctx.join(forked);
+            let (ids, elapsed) = r?;
+            results.push(StepResult {
+                ids,
+                scans,
+                elapsed,
             });
         }
+    }
+    Ok(results)
+}
 
-        for id in ids {
-            let e = result_ids.entry(id).and_modify(|c| *c += 1);
-            if first_predicate {
-                // As no result ID that appears in a later scan but not the
-                // first scan can be in the final result set, don't use memory
-                // to store them.
-                e.or_insert(1);
+// Step is a unit of work within a conjunction: either a predicate
+// that is evaluated by itself, a single index scan collapsed from
+// one or more range predicates on the same field, or E predicates
+// that are intersected together.
+enum Step {
+    Eval(QP),
+    Eqs(Vec<QP>),
+    Scan {
+        // The path of the scan, for explaining it
+        p: Vec<TaggableValue>,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        // Whether only the values at exactly p match, and not the
+        // fields below p with keys in the range; see is_exact.
+        exact: bool,
+    },
+}
+
+fn eval_step(ctx: &mut Ctx, step: Step) -> Result<RoaringTreemap, DocDbError> {
+    let ids = match step {
+        Step::Eval(qp) => eval(ctx, qp)?,
+        Step::Eqs(qps) => eval_eqs(ctx, qps)?,
+        Step::Scan {
+            p,
+            start_key,
+            end_key,
+            exact,
+        } => {
+            let p_len = exact.then(|| encoding::encode_index_query_p_start_key(&p).len());
+            let (ids, scan) = scan(ctx, &start_key, &end_key, p_len)?;
+            record_scan(ctx, &p, &start_key, &end_key, scan);
+            ids
+        }
+    };
+    ctx.limits.hold_ids(ids.len())?;
+    Ok(ids)
+}
+
+// Describes a step for explaining the query and its stats
+fn describe_step(step: &Step) -> String {
+    match step {
+        Step::Eval(qp) => qp.to_string(),
+        Step::Eqs(qps) => {
+            let qps: Vec<String> = qps.iter().map(|qp| qp.to_string()).collect();
+            qps.join(" AND ")
+        }
+        Step::Scan { p, .. } => format!("ranges of {}", DisplayPath(p)),
+    }
+}
+
+// Replaces the E predicates in steps with a single Eqs step, at the
+// position of the first, if there's more than one.
+fn merge_eqs(steps: Vec<Step>) -> Vec<Step> {
+    let n_eqs = steps
+        .iter()
+        .filter(|step| matches!(step, Step::Eval(QP::E { .. })))
+        .count();
+    if n_eqs < 2 {
+        return steps;
+    }
+    let mut merged = vec![];
+    let mut eqs = vec![];
+    for step in steps {
+        match step {
+            Step::Eval(qp @ QP::E { .. }) => {
+                if eqs.is_empty() {
+                    merged.push(Step::Eqs(vec![]));
+                }
+                eqs.push(qp);
             }
+            step => merged.push(step),
         }
+    }
+    for step in merged.iter_mut() {
+        if let Step::Eqs(qps) = step {
+            *qps = std::mem::take(&mut eqs);
+        }
+    }
+    merged
+}
 
-        first_predicate = false;
+// Intersects the results of E predicates. The keys for a path and
+// value are in doc ID order, so rather than reading each scan in full,
+// we read them in turn, skipping each forward to the highest doc ID
+// seen so far. When one scan has few keys, this avoids reading most
+// of the others.
+fn eval_eqs(ctx: &mut Ctx, qps: Vec<QP>) -> Result<RoaringTreemap, DocDbError> {
+    let mut seekers = vec![];
+    for qp in &qps {
+        if let QP::E { p, v } = qp {
+            let scan = ctx.stats.start_scan();
+            seekers.push(Seeker::new(ctx.db, scan, p, v));
+        }
     }
 
-    // Only those entries which were found in every index scan
-    // should be in the final result set.
-    let mut results = vec![];
-    for (id, n) in result_ids {
-        if n == n_preds {
-            results.push(id);
+    let mut ids = RoaringTreemap::new();
+    // The doc ID every scan is moved to, and how many are there
+    let mut target = String::new();
+    let mut agreed = 0;
+    let mut i = 0;
+    while let Some((id, ordinal)) = seekers[i].seek(ctx, &target)? {
+        if id == target {
+            agreed += 1;
+        } else {
+            target = id;
+            agreed = 1;
+        }
+        if agreed == seekers.len() {
+            ids.insert(ordinal);
+            ctx.limits.hold_ids(ids.len())?;
+            // The next doc ID after target
+            target.push('\0');
+            agreed = 0;
         }
+        i = (i + 1) % seekers.len();
     }
 
-    Ok(QueryResult { results, stats })
+    for (qp, seeker) in qps.iter().zip(seekers) {
+        if let QP::E { p, v } = qp {
+            let start_key = encoding::encode_index_query_pv_start_key(p, v);
+            record_scan(ctx, p, &start_key, &seeker.end_key, seeker.scan);
+        }
+    }
+    Ok(ids)
 }
 
-fn lookup_eq(
-    db: &Db,
-    path: Vec<TaggableValue>,
-    v: TaggableValue,
-) -> Result<Vec<String>, DocDbError> {
-    let start_key = encoding::encode_index_query_pv_start_key(&path, &v);
-    let end_key = encoding::encode_index_query_pv_end_key(&path, &v);
-    scan(&db, &start_key, &end_key)
+// Seeker reads the keys of an E predicate's scan in doc ID order, and
+// can skip forward to a doc ID.
+struct Seeker<'a> {
+    db: &'a Db,
+    // The index of the scan in the query's stats
+    scan: usize,
+    p: &'a Vec<TaggableValue>,
+    v: &'a TaggableValue,
+    // Every key for p and v starts with prefix
+    prefix: Vec<u8>,
+    // The length of the keys' path prefix, for is_key_at_path
+    p_len: usize,
+    end_key: Vec<u8>,
+    iter: sled::Iter,
 }
 
-fn lookup_gte(
-    db: &Db,
-    path: Vec<TaggableValue>,
-    v: TaggableValue,
-) -> Result<Vec<String>, DocDbError> {
-    let start_key = encoding::encode_index_query_pv_start_key(&path, &v);
-    let end_key = encoding::encode_index_query_p_end_key(&path);
-    scan(&db, &start_key, &end_key)
+// How many keys to step through to reach a doc ID before seeking to
+// it, which is slower than reading the next key.
+const SEEK_AFTER_STEPS: usize = 8;
+
+impl<'a> Seeker<'a> {
+    fn new(db: &'a Db, scan: usize, p: &'a Vec<TaggableValue>, v: &'a TaggableValue) -> Self {
+        let prefix = encoding::encode_index_query_pv_start_key(p, v);
+        let end_key = encoding::encode_index_query_pv_end_key(p, v);
+        Seeker {
+            db,
+            scan,
+            p,
+            v,
+            p_len: encoding::encode_index_query_p_start_key(p).len(),
+            iter: db.range(prefix.as_slice()..end_key.as_slice()),
+            prefix,
+            end_key,
+        }
+    }
+
+    // Returns the first doc ID at or after target, with its ordinal.
+    fn seek(&mut self, ctx: &mut Ctx, target: &str) -> Result<Option<(String, u64)>, DocDbError> {
+        for _ in 0..SEEK_AFTER_STEPS {
+            match self.next(ctx)? {
+                Some((id, ordinal)) if id.as_str() >= target => return Ok(Some((id, ordinal))),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+        let start_key = encoding::encode_index_key(target, self.p, self.v);
+        self.iter = self.db.range(start_key..self.end_key.clone());
+        self.next(ctx)
+    }
+
+    fn next(&mut self, ctx: &mut Ctx) -> Result<Option<(String, u64)>, DocDbError> {
+        for kv in self.iter.by_ref() {
+            let (k, v) = kv?;
+            ctx.read_key(self.scan, &k, &v)?;
+            // Keys for fields below p can be within the scan, eg, for
+            // p `a` and v "b", those of `a.b`. They're skipped, as by
+            // the other scans, which also keeps the keys in doc ID order.
+            if !encoding::is_key_at_path(&k, self.p_len) {
+                continue;
+            }
+            match (
+                encoding::decode_index_key_docid(&k[self.prefix.len()..]),
+                encoding::decode_ordinal(&v),
+            ) {
+                (Ok(id), Ok(ordinal)) => return Ok(Some((id.to_string(), ordinal))),
+                _ => ctx.stats.decode_failures += 1,
+            }
+        }
+        Ok(None)
+    }
 }
 
-fn lookup_gt(
-    db: &Db,
-    path: Vec<TaggableValue>,
-    v: TaggableValue,
-) -> Result<Vec<String>, DocDbError> {
-    let start_key = encoding::encode_index_query_pv_end_key(&path, &v);
-    let end_key = encoding::encode_index_query_p_end_key(&path);
-    scan(&db, &start_key, &end_key)
+// Records an index scan for the query's explanation, if it has one.
+// scan is the index of the scan in the query's stats.
+fn record_scan(
+    ctx: &mut Ctx,
+    p: &Vec<TaggableValue>,
+    start_key: &[u8],
+    end_key: &[u8],
+    scan: usize,
+) {
+    let keys = ctx.stats.scan_keys[scan];
+    if let Some(scans) = ctx.scans.as_mut() {
+        scans.push(ExplainScan {
+            p: p.clone(),
+            array_sizes: encoding::is_array_size_key(start_key),
+            start: describe_bound(p, start_key),
+            end: describe_bound(p, end_key),
+            keys,
+        });
+    }
 }
 
-fn lookup_lt(
-    db: &Db,
-    path: Vec<TaggableValue>,
-    v: TaggableValue,
-) -> Result<Vec<String>, DocDbError> {
-    let start_key = encoding::encode_index_query_p_start_key(&path);
-    let end_key = encoding::encode_index_query_pv_start_key(&path, &v);
-    scan(&db, &start_key, &end_key)
+// Groups the range predicates in qps by their field, and collapses
+// each group into the smallest index scan satisfying every predicate
+// in the group. See docs/001-simple-AND-optimisations.md.
+// Returns None if any field's ranges don't overlap, as then the
+// conjunction cannot have any results.
+fn collapse_ranges(qps: Vec<QP>, strict_types: bool) -> Option<Vec<Step>> {
+    // Group by encoded path so that, eg, String and ArcString
+    // components with the same content are the same field. Groups
+    // keep the position of their first predicate, so the steps
+    // stay in the order of qps.
+    let mut groups: Vec<(Option<Vec<u8>>, Vec<QP>)> = vec![];
+    for qp in qps {
+        let field = match range_keys(&qp, strict_types) {
+            // Don't collapse predicates on AnyIndex paths, as each
+            // predicate can be satisfied by a different array element.
+            Some((p, _, _)) if !p.contains(&TaggableValue::AnyIndex) => match qp {
+                // Array sizes are a separate set of keys to the values
+                QP::Size { .. } => Some(encoding::array_size_query_lower_bound(p, None)),
+                _ => Some(encoding::encode_index_query_p_start_key(p)),
+            },
+            _ => None,
+        };
+        let group = match field {
+            Some(_) => groups.iter_mut().find(|(f, _)| *f == field),
+            None => None,
+        };
+        match group {
+            Some((_, group)) => group.push(qp),
+            None => groups.push((field, vec![qp])),
+        }
+    }
+
+    let mut steps = vec![];
+    for (field, mut group) in groups {
+        if field.is_none() {
+            steps.push(Step::Eval(group.remove(0)));
+            continue;
+        }
+        // Take the highest start key and lowest end key.
+        let mut keys = group.iter().filter_map(|qp| range_keys(qp, strict_types));
+        let (p, mut start_key, mut end_key) = keys.next()?;
+        for (_, s, e) in keys {
+            start_key = start_key.max(s);
+            end_key = end_key.min(e);
+        }
+        if start_key >= end_key {
+            return None;
+        }
+        // A predicate by itself is left as it is, to explain it as itself
+        if group.len() == 1 {
+            steps.push(Step::Eval(group.remove(0)));
+            continue;
+        }
+        let p = p.clone();
+        // The overlap of an exact range with any other is exact
+        let exact = group.iter().any(is_exact);
+        steps.push(Step::Scan {
+            p,
+            start_key,
+            end_key,
+            exact,
+        });
+    }
+    Some(steps)
 }
 
-fn lookup_lte(
-    db: &Db,
-    path: Vec<TaggableValue>,
-    v: TaggableValue,
-) -> Result<Vec<String>, DocDbError> {
-    let start_key = encoding::encode_index_query_p_start_key(&path);
-    let end_key = encoding::encode_index_query_pv_end_key(&path, &v);
-    scan(&db, &start_key, &end_key)
-}
-
-fn scan(db: &Db, start_key: &[u8], end_key: &[u8]) -> Result<Vec<String>, DocDbError> {
-    let mut ids = vec![];
-    let iter = db.range(start_key..end_key);
-    for i in iter {
-        let (k, _) = i?;
-        match encoding::decode_index_key_docid(&k) {
-            Ok(v) => ids.push(v.to_string()),
-            Err(_) => println!("Couldn't decode docID from {:?}", &k),
+// Returns the path and the start and end keys of the index
+// scan for a range predicate, or None for other predicates.
+fn range_keys(qp: &QP, strict_types: bool) -> Option<(&Vec<TaggableValue>, Vec<u8>, Vec<u8>)> {
+    use encoding::{
+        encode_index_query_p_end_key as p_end, encode_index_query_p_start_key as p_start,
+        encode_index_query_pv_end_key as pv_end, encode_index_query_pv_start_key as pv_start,
+    };
+    let (p, start_key, end_key) = match qp {
+        QP::E { p, v } => (p, pv_start(p, v), pv_end(p, v)),
+        QP::GT { p, v } => (p, pv_end(p, v), p_end(p)),
+        QP::GTE { p, v } => (p, pv_start(p, v), p_end(p)),
+        QP::LT { p, v } => (p, p_start(p), pv_start(p, v)),
+        QP::LTE { p, v } => (p, p_start(p), pv_end(p, v)),
+        QP::Prefix { p, prefix } => (
+            p,
+            encoding::encode_index_query_prefix_start_key(p, prefix),
+            encoding::encode_index_query_prefix_end_key(p, prefix),
+        ),
+        QP::Exists { p } => (p, p_start(p), p_end(p)),
+        QP::Size { p, op, n } => {
+            use encoding::{
+                array_size_query_lower_bound as lower, array_size_query_upper_bound as upper,
+            };
+            match op {
+                Cmp::E => (p, lower(p, Some(*n)), upper(p, Some(*n))),
+                Cmp::GT => (p, upper(p, Some(*n)), upper(p, None)),
+                Cmp::GTE => (p, lower(p, Some(*n)), upper(p, None)),
+                Cmp::LT => (p, lower(p, None), lower(p, Some(*n))),
+                Cmp::LTE => (p, lower(p, None), upper(p, Some(*n))),
+            }
+        }
+        QP::Type { p, t } => (
+            p,
+            encoding::query_type_lower_bound(p, *t),
+            encoding::query_type_upper_bound(p, *t),
+        ),
+        QP::In { .. }
+        | QP::NE { .. }
+        | QP::NotIn { .. }
+        | QP::Match { .. }
+        | QP::ElemMatch { .. }
+        | QP::And(_)
+        | QP::Or(_)
+        | QP::Missing { .. }
+        | QP::Not(_) => return None,
+    };
+    let t = match qp {
+        QP::GT { v, .. } | QP::GTE { v, .. } | QP::LT { v, .. } | QP::LTE { v, .. }
+            if strict_types =>
+        {
+            v.json_type()
+        }
+        _ => None,
+    };
+    match t {
+        // Clamp the range to values of the predicate value's type.
+        Some(t) => Some((
+            p,
+            start_key.max(encoding::query_type_lower_bound(p, t)),
+            end_key.min(encoding::query_type_upper_bound(p, t)),
+        )),
+        None => Some((p, start_key, end_key)),
+    }
+}
+
+// Returns whether the scan of range predicate qp only matches values at
+// exactly its path. The range for a path also has the keys of fields
+// below it, as the next component of their path is where the value
+// would be, eg, `a.b` has keys in the String range of `a`. Only Exists
+// matches them, as it matches objects with a field.
+fn is_exact(qp: &QP) -> bool {
+    !matches!(qp, QP::Exists { .. })
+}
+
+// Scans each range in turn and returns the union of their IDs. Only
+// the values at exactly p match, as ranges from complement_ranges
+// span the keys of every field below p.
+fn eval_ranges(
+    ctx: &mut Ctx,
+    p: &[TaggableValue],
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<RoaringTreemap, DocDbError> {
+    let steps = ranges
+        .into_iter()
+        .map(|(start_key, end_key)| Step::Scan {
+            p: p.to_vec(),
+            start_key,
+            end_key,
+            exact: true,
+        })
+        .collect();
+    let mut ids = RoaringTreemap::new();
+    for r in eval_steps(ctx, steps)? {
+        ids |= r.ids;
+        ctx.limits.hold_ids(ids.len())?;
+        ctx.add_scans(r.scans);
+    }
+    Ok(ids)
+}
+
+// Returns the equality scan ranges for each of vs at path p. They are
+// sorted into key order, so scanning them means we only ever move
+// forward through the index.
+fn eq_ranges(p: &Vec<TaggableValue>, vs: &[TaggableValue]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut ranges: Vec<(Vec<u8>, Vec<u8>)> = vs
+        .iter()
+        .map(|v| {
+            (
+                encoding::encode_index_query_pv_start_key(p, v),
+                encoding::encode_index_query_pv_end_key(p, v),
+            )
+        })
+        .collect();
+    ranges.sort();
+    ranges.dedup();
+    ranges
+}
+
+// Returns the ranges covering every value at path p apart from
+// those in the sorted, non-overlapping ranges.
+fn complement_ranges(
+    p: &Vec<TaggableValue>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut complement = vec![];
+    let mut start_key = encoding::encode_index_query_p_start_key(p);
+    for (s, e) in ranges {
+        if start_key < s {
+            complement.push((start_key, s));
+        }
+        start_key = e;
+    }
+    let end_key = encoding::encode_index_query_p_end_key(p);
+    if start_key < end_key {
+        complement.push((start_key, end_key));
+    }
+    complement
+}
+
+// Evaluates a Match against the full-text index, recording the
+// scores of the matching documents for ranking the results.
+fn eval_match(
+    ctx: &mut Ctx,
+    p: Vec<TaggableValue>,
+    q: String,
+) -> Result<RoaringTreemap, DocDbError> {
+    let opts = ctx.opts;
+    let default_analyzer;
+    let analyzer: &dyn Analyzer = match &opts.analyzer {
+        Some(a) => a.as_ref(),
+        None => {
+            default_analyzer = StandardAnalyzer::default();
+            &default_analyzer
+        }
+    };
+    let scores = text::search(ctx, &p, &q, analyzer)?;
+    let mut ids = RoaringTreemap::new();
+    for (id, score) in scores {
+        if let Some(ordinal) = ctx.db.get(encoding::encode_document_ordinal_key(&id))? {
+            match encoding::decode_ordinal(&ordinal) {
+                Ok(ordinal) => ids.insert(ordinal),
+                Err(_) => continue,
+            };
+        }
+        *ctx.scores.entry(id).or_insert(0.0) += score;
+    }
+    Ok(ids)
+}
+
+// Evaluates an ElemMatch by scanning every index entry under p and
+// checking the entries for each array element against qps. As keys
+// are ordered by array index before document ID, the entries for an
+// element aren't adjacent, so we track which leaf predicates each
+// element has satisfied so far.
+fn eval_elem_match(
+    ctx: &mut Ctx,
+    p: Vec<TaggableValue>,
+    qps: Vec<QP>,
+) -> Result<RoaringTreemap, DocDbError> {
+    if p.contains(&TaggableValue::AnyIndex) {
+        return Err(DocDbError::InvalidQuery(
+            "ElemMatch path cannot contain AnyIndex".to_string(),
+        ));
+    }
+    let qp = QP::And(qps);
+    let mut leaves = vec![];
+    elem_match_leaves(&qp, &mut leaves)?;
+
+    // (ordinal, array index) => which leaves the element satisfies
+    let mut elements: BTreeMap<(u64, u64), Vec<bool>> = BTreeMap::new();
+    let start_key = encoding::encode_index_query_p_start_key(&p);
+    let end_key = encoding::encode_index_query_p_end_key(&p);
+    let scan = ctx.stats.start_scan();
+    for i in ctx.db.range(start_key.as_slice()..end_key.as_slice()) {
+        let (k, ordinal) = i?;
+        ctx.read_key(scan, &k, &ordinal)?;
+        let (path, v, ordinal) = match (
+            encoding::decode_index_key(&k),
+            encoding::decode_ordinal(&ordinal),
+        ) {
+            (Ok((path, v, _)), Ok(ordinal)) => (path, v, ordinal),
+            _ => {
+                ctx.stats.decode_failures += 1;
+                continue;
+            }
+        };
+        // Only entries below an array index of p are elements, which
+        // also skips the AnyIndex entries for the array.
+        let idx = match path.get(p.len()) {
+            Some(TaggableValue::Number(idx)) => *idx as u64,
+            _ => continue,
+        };
+        let rest = &path[p.len() + 1..];
+        let satisfied = elements
+            .entry((ordinal, idx))
+            .or_insert_with(|| vec![false; leaves.len()]);
+        for (j, leaf) in leaves.iter().enumerate() {
+            satisfied[j] |= leaf_matches(leaf, rest, &v, ctx.opts.strict_types);
+        }
+    }
+
+    record_scan(ctx, &p, &start_key, &end_key, scan);
+
+    let mut ids = RoaringTreemap::new();
+    for ((ordinal, _), satisfied) in elements {
+        if elem_match_eval(&qp, &satisfied, &mut 0) {
+            ids.insert(ordinal);
+        }
+    }
+    Ok(ids)
+}
+
+// Collects the leaf predicates of qp in depth-first order, which
+// is the order elem_match_eval visits them.
+fn elem_match_leaves<'a>(qp: &'a QP, leaves: &mut Vec<&'a QP>) -> Result<(), DocDbError> {
+    match qp {
+        QP::And(qps) | QP::Or(qps) => {
+            for qp in qps {
+                elem_match_leaves(qp, leaves)?;
+            }
+        }
+        QP::Not(qp) => elem_match_leaves(qp, leaves)?,
+        QP::ElemMatch { .. } => {
+            return Err(DocDbError::InvalidQuery(
+                "ElemMatch cannot be nested within ElemMatch".to_string(),
+            ))
+        }
+        QP::Size { .. } => {
+            return Err(DocDbError::InvalidQuery(
+                "Size cannot be used within ElemMatch".to_string(),
+            ))
+        }
+        QP::Match { .. } => {
+            return Err(DocDbError::InvalidQuery(
+                "Match cannot be used within ElemMatch".to_string(),
+            ))
+        }
+        leaf => leaves.push(leaf),
+    }
+    Ok(())
+}
+
+// Evaluates qp for one array element, given whether the element
+// satisfied each leaf predicate. next is the index of the next leaf.
+fn elem_match_eval(qp: &QP, satisfied: &[bool], next: &mut usize) -> bool {
+    match qp {
+        // Evaluate every child so that next stays in step with the leaves
+        QP::And(qps) | QP::Or(qps) => {
+            let ms: Vec<bool> = qps
+                .iter()
+                .map(|qp| elem_match_eval(qp, satisfied, next))
+                .collect();
+            match qp {
+                QP::And(_) => ms.iter().all(|m| *m),
+                _ => ms.iter().any(|m| *m),
+            }
+        }
+        QP::Not(qp) => !elem_match_eval(qp, satisfied, next),
+        QP::Missing { .. } => {
+            *next += 1;
+            !satisfied[*next - 1]
+        }
+        _ => {
+            *next += 1;
+            satisfied[*next - 1]
+        }
+    }
+}
+
+// Returns whether the value v at relative path rest satisfies the
+// leaf predicate qp. rest has the element's array indexes, which
+// AnyIndex components of the predicate's path match. Values are
+// compared in their encoded form so that the ordering is the same
+// as in the index.
+fn leaf_matches(qp: &QP, rest: &[TaggableValue], v: &TaggableValue, strict_types: bool) -> bool {
+    let ev = encoding::encode_value(v);
+    let cmp = |p: &Vec<TaggableValue>, qv: &TaggableValue| {
+        if !path_matches(p, rest) {
+            return None;
+        }
+        Some(ev.cmp(&encoding::encode_value(qv)))
+    };
+    let typed = |qv: &TaggableValue| !strict_types || v.json_type() == qv.json_type();
+    use std::cmp::Ordering::{Equal, Greater, Less};
+    match qp {
+        QP::E { p, v: qv } => cmp(p, qv) == Some(Equal),
+        QP::NE { p, v: qv } => matches!(cmp(p, qv), Some(Less | Greater)),
+        QP::GT { p, v: qv } => cmp(p, qv) == Some(Greater) && typed(qv),
+        QP::GTE { p, v: qv } => matches!(cmp(p, qv), Some(Greater | Equal)) && typed(qv),
+        QP::LT { p, v: qv } => cmp(p, qv) == Some(Less) && typed(qv),
+        QP::LTE { p, v: qv } => matches!(cmp(p, qv), Some(Less | Equal)) && typed(qv),
+        QP::In { p, vs } => vs.iter().any(|qv| cmp(p, qv) == Some(Equal)),
+        QP::NotIn { p, vs } => {
+            path_matches(p, rest) && vs.iter().all(|qv| cmp(p, qv) != Some(Equal))
+        }
+        QP::Prefix { p, prefix } => {
+            path_matches(p, rest)
+                && match v {
+                    TaggableValue::String(s) => s.starts_with(prefix.as_str()),
+                    _ => false,
+                }
+        }
+        QP::Type { p, t } => path_matches(p, rest) && v.json_type() == Some(*t),
+        // Like the index, a path exists if it has a value or
+        // is an object with a field.
+        QP::Exists { p } | QP::Missing { p } => {
+            p.len() <= rest.len() && path_matches(p, &rest[..p.len()])
+        }
+        QP::Size { .. }
+        | QP::Match { .. }
+        | QP::ElemMatch { .. }
+        | QP::And(_)
+        | QP::Or(_)
+        | QP::Not(_) => false,
+    }
+}
+
+fn eval_or(ctx: &mut Ctx, qps: Vec<QP>) -> Result<RoaringTreemap, DocDbError> {
+    let steps = qps.into_iter().map(Step::Eval).collect();
+    let mut result_ids = RoaringTreemap::new();
+    for r in eval_steps(ctx, steps)? {
+        result_ids |= r.ids;
+        ctx.limits.hold_ids(result_ids.len())?;
+        ctx.add_scans(r.scans);
+    }
+    Ok(result_ids)
+}
+
+// Returns the IDs of every document in the database.
+pub(crate) fn all_docids(db: &Db, stats: &mut QueryStats) -> Result<BTreeSet<String>, DocDbError> {
+    let mut ids = BTreeSet::new();
+    let start_key = encoding::encode_document_query_start_key();
+    let end_key = encoding::encode_document_query_end_key();
+    let scan = stats.start_scan();
+    for i in db.range(start_key..end_key) {
+        let (k, v) = i?;
+        stats.read_key(scan, &k, &v);
+        match encoding::decode_document_key_docid(&k) {
+            Ok(v) => {
+                ids.insert(v.to_string());
+            }
+            Err(_) => stats.decode_failures += 1,
         };
     }
     Ok(ids)
 }
 
+// Returns the ordinals of every document in the database.
+fn all_ordinals(ctx: &mut Ctx) -> Result<RoaringTreemap, DocDbError> {
+    let mut ids = RoaringTreemap::new();
+    let start_key = encoding::encode_ordinal_query_start_key();
+    let end_key = encoding::encode_ordinal_query_end_key();
+    let scan = ctx.stats.start_scan();
+    for i in ctx.db.range(start_key..end_key) {
+        let (k, v) = i?;
+        ctx.read_key(scan, &k, &v)?;
+        match encoding::decode_ordinal_key(&k) {
+            Ok(ordinal) => {
+                ids.insert(ordinal);
+            }
+            Err(_) => ctx.stats.decode_failures += 1,
+        };
+    }
+    Ok(ids)
+}
+
+// Returns the doc IDs of ordinals. Ordinals of documents deleted since
+// they were read are skipped.
+fn docids(db: &Db, ids: &RoaringTreemap) -> Result<BTreeSet<String>, DocDbError> {
+    let mut docids = BTreeSet::new();
+    for ordinal in ids {
+        if let Some(docid) = db.get(encoding::encode_ordinal_key(ordinal))? {
+            match std::str::from_utf8(&docid) {
+                Ok(docid) => docids.insert(docid.to_string()),
+                Err(_) => continue,
+            };
+        }
+    }
+    Ok(docids)
+}
+
+// Scans the index between start_key and end_key, returning the
+// ordinals found and the index of the scan in the query's stats. With
+// p_len, the length of the keys' path prefix, only the keys of values
+// at exactly that path are read, as in encoding::is_key_at_path.
+fn scan(
+    ctx: &mut Ctx,
+    start_key: &[u8],
+    end_key: &[u8],
+    p_len: Option<usize>,
+) -> Result<(RoaringTreemap, usize), DocDbError> {
+    let mut ids = RoaringTreemap::new();
+    let mut n_ids = 0;
+    let scan = ctx.stats.start_scan();
+    for i in ctx.db.range(start_key..end_key) {
+        let (k, v) = i?;
+        ctx.read_key(scan, &k, &v)?;
+        if p_len.is_some_and(|p_len| !encoding::is_key_at_path(&k, p_len)) {
+            continue;
+        }
+        match encoding::decode_ordinal(&v) {
+            Ok(ordinal) => {
+                if ids.insert(ordinal) {
+                    n_ids += 1;
+                    ctx.limits.hold_ids(n_ids)?;
+                }
+            }
+            Err(_) => ctx.stats.decode_failures += 1,
+        };
+    }
+    Ok((ids, scan))
+}
+
+// Returns an iterator over the IDs matching q, which reads them from
+// the index as it goes rather than collecting them, so it uses the
+// same memory whatever the number of results. Only some queries can
+// be evaluated this way, and others return an InvalidQuery error:
+//
+// - range predicates on a single field, in index order. Predicates on
+//   AnyIndex paths must be E, as otherwise a document can be found
+//   more than once.
+// - E predicates on several fields, in ID order.
+//
+// Exists isn't supported, as it also matches objects, so unlike the
+// other predicates can't only read the values at exactly its path.
+pub fn search_index_iter(db: &Db, mut q: Query) -> Result<QueryIter, DocDbError> {
+    let unsupported = || {
+        DocDbError::InvalidQuery(
+            "search_index_iter supports range predicates on a single field or \
+             E predicates; use search_index for other queries"
+                .to_string(),
+        )
+    };
+    check_paths(&q)?;
+    let all_eq = q.iter().all(|qp| matches!(qp, QP::E { .. }));
+    for qp in &q {
+        match range_keys(qp, false) {
+            Some((p, _, _)) if p.contains(&TaggableValue::AnyIndex) && !all_eq => {
+                return Err(unsupported())
+            }
+            _ if matches!(qp, QP::Exists { .. }) => return Err(unsupported()),
+            Some(_) => {}
+            None => return Err(unsupported()),
+        }
+    }
+
+    q.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let steps = match collapse_ranges(q, false) {
+        Some(steps) if !steps.is_empty() => steps,
+        _ => {
+            return Ok(QueryIter {
+                scans: vec![],
+                decode_failures: 0,
+            })
+        }
+    };
+    if steps.len() > 1 && !all_eq {
+        return Err(unsupported());
+    }
+    let scans = steps
+        .into_iter()
+        .filter_map(|step| match step {
+            Step::Scan {
+                p,
+                start_key,
+                end_key,
+                ..
+            } => Some((p, start_key, end_key)),
+            // Predicates on AnyIndex paths aren't collapsed
+            Step::Eval(qp) => range_keys(&qp, false).map(|(p, s, e)| (p.clone(), s, e)),
+            // Only eval_and merges E predicates
+            Step::Eqs(_) => None,
+        })
+        .map(|(p, start_key, end_key)| {
+            let p_len = encoding::encode_index_query_p_start_key(&p).len();
+            (db.range(start_key..end_key), p_len)
+        })
+        .collect();
+    Ok(QueryIter {
+        scans,
+        decode_failures: 0,
+    })
+}
+
+// QueryIter yields the IDs of search_index_iter's results. With more
+// than one scan, each scan is of a single value, so is in ID order,
+// and the iterator returns the IDs they have in common by advancing
+// whichever scan is behind.
+pub struct QueryIter {
+    // Each scan, with the length of its keys' path prefix. Keys for
+    // fields below a scan's path can be within its range, and are
+    // skipped as in encoding::is_key_at_path.
+    scans: Vec<(sled::Iter, usize)>,
+    decode_failures: u64,
+}
+
+impl QueryIter {
+    // The number of index keys skipped so far as they couldn't be
+    // decoded, as in QueryStats.
+    pub fn decode_failures(&self) -> u64 {
+        self.decode_failures
+    }
+
+    // The next doc ID from scan i
+    fn next_id(&mut self, i: usize) -> Option<Result<String, DocDbError>> {
+        let (scan, p_len) = &mut self.scans[i];
+        for kv in scan.by_ref() {
+            let (k, _) = match kv {
+                Ok(kv) => kv,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match encoding::decode_index_key(&k) {
+                Ok(_) if !encoding::is_key_at_path(&k, *p_len) => {}
+                Ok((_, _, docid)) => return Some(Ok(docid)),
+                Err(_) => self.decode_failures += 1,
+            };
+        }
+        None
+    }
+}
+
+impl Iterator for QueryIter {
+    type Item = Result<String, DocDbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.scans.is_empty() {
+            return None;
+        }
+        let mut target = match self.next_id(0)? {
+            Ok(id) => id,
+            Err(e) => return Some(Err(e)),
+        };
+        // The number of scans, ending with scan i - 1, that are at target
+        let mut agreed = 1;
+        let mut i = 1 % self.scans.len();
+        while agreed < self.scans.len() {
+            let id = loop {
+                match self.next_id(i)? {
+                    Ok(id) if id >= target => break id,
+                    Ok(_) => continue,
+                    Err(e) => return Some(Err(e)),
+                }
+            };
+            if id == target {
+                agreed += 1;
+            } else {
+                target = id;
+                agreed = 1;
+            }
+            i = (i + 1) % self.scans.len();
+        }
+        Some(Ok(target))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{docdb, keypath};
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use tempfile::tempdir;
 
     use super::*;
 
+    // Scans the index range for a single predicate, returning
+    // the doc IDs in index order.
+    fn lookup(db: &Db, qp: QP) -> Result<Vec<String>, DocDbError> {
+        let (_, start_key, end_key) = range_keys(&qp, false).unwrap();
+        let mut ids = vec![];
+        for i in db.range(start_key..end_key) {
+            let (k, _) = i?;
+            ids.push(encoding::decode_index_key_docid(&k).unwrap().to_string());
+        }
+        Ok(ids)
+    }
+    fn lookup_eq(
+        db: &Db,
+        p: Vec<TaggableValue>,
+        v: TaggableValue,
+    ) -> Result<Vec<String>, DocDbError> {
+        lookup(db, QP::E { p, v })
+    }
+    fn lookup_gt(
+        db: &Db,
+        p: Vec<TaggableValue>,
+        v: TaggableValue,
+    ) -> Result<Vec<String>, DocDbError> {
+        lookup(db, QP::GT { p, v })
+    }
+    fn lookup_gte(
+        db: &Db,
+        p: Vec<TaggableValue>,
+        v: TaggableValue,
+    ) -> Result<Vec<String>, DocDbError> {
+        lookup(db, QP::GTE { p, v })
+    }
+    fn lookup_lt(
+        db: &Db,
+        p: Vec<TaggableValue>,
+        v: TaggableValue,
+    ) -> Result<Vec<String>, DocDbError> {
+        lookup(db, QP::LT { p, v })
+    }
+    fn lookup_lte(
+        db: &Db,
+        p: Vec<TaggableValue>,
+        v: TaggableValue,
+    ) -> Result<Vec<String>, DocDbError> {
+        lookup(db, QP::LTE { p, v })
+    }
+    fn lookup_exists(db: &Db, p: Vec<TaggableValue>) -> Result<Vec<String>, DocDbError> {
+        lookup(db, QP::Exists { p })
+    }
+
     fn insert_test_data(db: &Db) -> Result<(), DocDbError> {
+        docdb::set_document(db, "doc1", json!({"a":{"b": 1}, "name": "mike", "age": 40}))?;
+        docdb::set_document(db, "doc2", json!({"a":{"c": 2}, "name": "john", "age": 24}))?;
         docdb::set_document(
-            &db,
-            "doc1",
-            json!({"a":{"b": 1}, "name": "mike", "age": 40}),
-        )?;
-        docdb::set_document(
-            &db,
-            "doc2",
-            json!({"a":{"c": 2}, "name": "john", "age": 24}),
-        )?;
-        docdb::set_document(
-            &db,
+            db,
             "doc3",
             json!({"a":{"c": 2}, "name": "john", "age": 110}),
         )?;
@@ -392,4 +2415,130 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn lookup_exists_test() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = docdb::new_database(tmp_dir.path()).unwrap();
+        insert_test_data(&db)?;
+
+        let ids = lookup_exists(&db, keypath!["a", "c"])?;
+        assert_eq!(vec!["doc2", "doc3"], ids);
+        let ids = lookup_exists(&db, keypath!["a", "b"])?;
+        assert_eq!(vec!["doc1"], ids);
+        // Objects exist if they have a field
+        let ids = lookup_exists(&db, keypath!["a"])?;
+        assert_eq!(vec!["doc1", "doc2", "doc3"], ids);
+        let ids = lookup_exists(&db, keypath!["email"])?;
+        assert_eq!(Vec::<String>::new(), ids);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_token_test() {
+        let cursors = vec![
+            Cursor::Id("doc1".to_string()),
+            Cursor::Score(1.5, "doc2".to_string()),
+            Cursor::Key(encoding::encode_index_key("doc3", &keypath!["a"], &tv(1))),
+            Cursor::Missing("".to_string()),
+        ];
+        for c in cursors {
+            assert_eq!(Cursor::decode(&c.encode()).unwrap(), c);
+        }
+        assert!(Cursor::decode("").is_err());
+        assert!(Cursor::decode("7").is_err());
+        assert!(Cursor::decode("7300").is_err());
+        assert!(Cursor::decode("zz").is_err());
+    }
+
+    #[test]
+    fn test_decode_failures() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = docdb::new_database(tmp_dir.path()).unwrap();
+        insert_test_data(&db)?;
+        // An index key without an ordinal is skipped
+        db.insert(
+            encoding::encode_index_key("doc9", &keypath!["age"], &tv(30)),
+            b"x",
+        )?;
+
+        let r = search_index(&db, parse("age >= 30").unwrap())?;
+        assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], r.results);
+        assert_eq!(1, r.stats.decode_failures);
+        assert_eq!(vec![3], r.stats.scan_keys);
+
+        // search_index_iter doesn't read ordinals, but skips keys that
+        // can't be decoded
+        db.remove(encoding::encode_index_key(
+            "doc9",
+            &keypath!["age"],
+            &tv(30),
+        ))?;
+        let mut k = encoding::encode_index_key("doc9", &keypath!["age"], &tv(31));
+        let last = k.len() - 4;
+        k[last] = 0xff;
+        db.insert(k, &encoding::encode_ordinal(9))?;
+        let mut iter = search_index_iter(&db, parse("age >= 30").unwrap())?;
+        let ids: Vec<String> = iter.by_ref().collect::<Result<_, _>>()?;
+        assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids);
+        assert_eq!(1, iter.decode_failures());
+        Ok(())
+    }
+
+    // Counts the threads evaluating steps: those started, those
+    // running and the most running at once.
+    static STARTED_WORKERS: AtomicUsize = AtomicUsize::new(0);
+    static WORKERS: AtomicUsize = AtomicUsize::new(0);
+    static MAX_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+    pub(super) struct Worker;
+
+    impl Worker {
+        pub(super) fn start() -> Worker {
+            STARTED_WORKERS.fetch_add(1, Ordering::SeqCst);
+            let n = WORKERS.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_WORKERS.fetch_max(n, Ordering::SeqCst);
+            Worker
+        }
+    }
+
+    impl Drop for Worker {
+        fn drop(&mut self) {
+            WORKERS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_parallelism_nested() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = docdb::new_database(tmp_dir.path()).unwrap();
+        insert_test_data(&db)?;
+
+        // Each branch of the OR is evaluated on its own thread, and
+        // the predicates of its AND in turn on that thread.
+        let q = parse(
+            "(age > 30 AND name > \"a\" AND a.b > 0) OR \
+             (age < 30 AND name < \"z\" AND a.c > 0)",
+        )
+        .unwrap();
+        let opts = QueryOptions {
+            parallelism: 2,
+            ..Default::default()
+        };
+        let r = search_index_with_options(&db, q, &opts)?;
+        assert_eq!(vec!["doc1".to_string(), "doc2".to_string()], r.results);
+        assert_eq!(2, STARTED_WORKERS.load(Ordering::SeqCst));
+        assert!(MAX_WORKERS.load(Ordering::SeqCst) <= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Query>();
+        assert_send::<QueryOptions>();
+        assert_send::<QueryIter>();
+        assert_send::<QueryResult>();
+    }
 }
@@ -0,0 +1,484 @@
+use std::error::Error;
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+use crate::docdb::DocDbError;
+use crate::query::{Cmp, JsonType, Query, TaggableValue, QP};
+
+// A selector is a JSON object describing a query, in the style of
+// MongoDB, eg:
+//
+//     {"name": "john", "age": {"$gt": 20}, "a.c": {"$lte": 2}}
+//
+// Fields are dotted paths. Components that are integers are array
+// indexes, and a `$[]` component matches any element of an array,
+// like TaggableValue::AnyIndex. Each field is ANDed together.
+//
+// Field operators: $eq, $ne, $gt, $gte, $lt, $lte, $in, $nin,
+// $exists, $type, $size, $not and $elemMatch.
+// Top-level operators: $and, $or and $nor.
+//
+// As in MongoDB, $ne and $nin also match documents without the field.
+// Unlike MongoDB, the empty selector `{}` is an error rather than
+// matching every document, here and within $and, $or, $nor and
+// $elemMatch.
+
+// An error for selectors that can't be parsed into a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectorError {
+    // Where in the selector the error is, eg, `$or.1.age.$gt`
+    pub at: String,
+    pub message: String,
+}
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.at.is_empty() {
+            write!(f, "invalid selector: {}", self.message)
+        } else {
+            write!(f, "invalid selector at `{}`: {}", self.at, self.message)
+        }
+    }
+}
+impl Error for SelectorError {}
+
+impl From<SelectorError> for DocDbError {
+    fn from(value: SelectorError) -> Self {
+        DocDbError::InvalidQuery(value.to_string())
+    }
+}
+
+fn err<T>(at: &str, message: String) -> Result<T, SelectorError> {
+    Err(SelectorError {
+        at: at.to_string(),
+        message,
+    })
+}
+
+// Parses a JSON selector into a Query.
+pub fn parse_selector(selector: &Value) -> Result<Query, SelectorError> {
+    parse_selector_at(selector, "")
+}
+
+fn parse_selector_at(selector: &Value, at: &str) -> Result<Query, SelectorError> {
+    let fields = match selector {
+        // An empty query matches nothing, rather than every document
+        // as in MongoDB, so an empty selector is an error.
+        Value::Object(fields) if fields.is_empty() => {
+            return err(
+                at,
+                "expected at least one field or operator, found an empty object".to_string(),
+            )
+        }
+        Value::Object(fields) => fields,
+        v => return err(at, format!("expected an object, found {}", describe(v))),
+    };
+    let mut qps = vec![];
+    for (k, v) in fields {
+        let at = location(at, k);
+        match k.as_str() {
+            "$and" => qps.extend(parse_selectors(v, &at)?),
+            "$or" => qps.push(QP::Or(parse_selectors(v, &at)?)),
+            "$nor" => qps.push(QP::Not(Box::new(QP::Or(parse_selectors(v, &at)?)))),
+            op if op.starts_with('$') => {
+                return err(
+                    &at,
+                    format!("unsupported operator `{}`; expected $and, $or or $nor", op),
+                )
+            }
+            field => qps.extend(parse_field(parse_path(field, &at)?, v, &at)?),
+        }
+    }
+    Ok(qps)
+}
+
+// Parses the array of selectors taken by $and, $or and $nor,
+// returning one predicate for each.
+fn parse_selectors(v: &Value, at: &str) -> Result<Vec<QP>, SelectorError> {
+    let selectors = match v {
+        Value::Array(selectors) if !selectors.is_empty() => selectors,
+        v => {
+            return err(
+                at,
+                format!(
+                    "expected a non-empty array of selectors, found {}",
+                    describe(v)
+                ),
+            )
+        }
+    };
+    let mut qps = vec![];
+    for (i, s) in selectors.iter().enumerate() {
+        qps.push(conjunction(parse_selector_at(
+            s,
+            &location(at, &i.to_string()),
+        )?));
+    }
+    Ok(qps)
+}
+
+// Splits a dotted field into a path.
+fn parse_path(field: &str, at: &str) -> Result<Vec<TaggableValue>, SelectorError> {
+    let mut p = vec![];
+    for c in field.split('.') {
+        p.push(match c {
+            "" => return err(at, "field paths can't have empty components".to_string()),
+            "$[]" => TaggableValue::AnyIndex,
+            c if c.bytes().all(|b| b.is_ascii_digit()) => match c.parse::<u32>() {
+                Ok(i) => TaggableValue::from(i as i64),
+                Err(_) => return err(at, format!("array index `{}` is too large", c)),
+            },
+            c => TaggableValue::from(c),
+        });
+    }
+    Ok(p)
+}
+
+// Parses the condition v on the field at path p. The condition is
+// either a value to compare for equality, or an object of operators.
+fn parse_field(p: Vec<TaggableValue>, v: &Value, at: &str) -> Result<Vec<QP>, SelectorError> {
+    match v {
+        Value::Object(ops) if ops.keys().any(|k| k.starts_with('$')) => parse_operators(p, ops, at),
+        Value::Object(_) => err(
+            at,
+            "objects can't be compared by value; use dotted paths to select their fields"
+                .to_string(),
+        ),
+        v => Ok(vec![QP::E {
+            p,
+            v: parse_value(v, at)?,
+        }]),
+    }
+}
+
+fn parse_operators(
+    p: Vec<TaggableValue>,
+    ops: &Map<String, Value>,
+    at: &str,
+) -> Result<Vec<QP>, SelectorError> {
+    let mut qps = vec![];
+    for (op, v) in ops {
+        let at = &location(at, op);
+        let p = p.clone();
+        qps.push(match op.as_str() {
+            "$eq" => QP::E {
+                p,
+                v: parse_value(v, at)?,
+            },
+            "$ne" => QP::Not(Box::new(QP::E {
+                p,
+                v: parse_value(v, at)?,
+            })),
+            "$gt" => QP::GT {
+                p,
+                v: parse_value(v, at)?,
+            },
+            "$gte" => QP::GTE {
+                p,
+                v: parse_value(v, at)?,
+            },
+            "$lt" => QP::LT {
+                p,
+                v: parse_value(v, at)?,
+            },
+            "$lte" => QP::LTE {
+                p,
+                v: parse_value(v, at)?,
+            },
+            "$in" => QP::In {
+                p,
+                vs: parse_values(v, at)?,
+            },
+            "$nin" => QP::Not(Box::new(QP::In {
+                p,
+                vs: parse_values(v, at)?,
+            })),
+            "$exists" => match v {
+                Value::Bool(true) => QP::Exists { p },
+                Value::Bool(false) => QP::Missing { p },
+                v => return err(at, format!("expected a boolean, found {}", describe(v))),
+            },
+            "$type" => QP::Type {
+                p,
+                t: match v.as_str() {
+                    Some("null") => JsonType::Null,
+                    Some("bool") => JsonType::Bool,
+                    Some("number") => JsonType::Number,
+                    Some("string") => JsonType::String,
+                    _ => {
+                        return err(
+                            at,
+                            format!(
+                                "expected one of \"null\", \"bool\", \"number\" or \"string\", found {}",
+                                v
+                            ),
+                        )
+                    }
+                },
+            },
+            "$size" => match v.as_u64() {
+                Some(n) => QP::Size {
+                    p,
+                    op: Cmp::E,
+                    n: n as usize,
+                },
+                None => {
+                    return err(
+                        at,
+                        format!("expected a non-negative integer, found {}", v),
+                    )
+                }
+            },
+            "$not" => match v {
+                Value::Object(ops) if !ops.is_empty() && ops.keys().all(|k| k.starts_with('$')) => {
+                    QP::Not(Box::new(conjunction(parse_operators(p, ops, at)?)))
+                }
+                v => {
+                    return err(
+                        at,
+                        format!("expected an object of operators, found {}", v),
+                    )
+                }
+            },
+            "$elemMatch" => QP::ElemMatch {
+                p,
+                qps: match v {
+                    // Operators apply to the elements themselves
+                    Value::Object(ops) if ops.keys().any(|k| k.starts_with('$')) => {
+                        parse_operators(vec![], ops, at)?
+                    }
+                    v => parse_selector_at(v, at)?,
+                },
+            },
+            field if !field.starts_with('$') => {
+                return err(at, "operators can't be mixed with fields".to_string())
+            }
+            op => {
+                return err(
+                    at,
+                    format!(
+                        "unsupported operator `{}`; expected one of $eq, $ne, $gt, $gte, $lt, \
+                         $lte, $in, $nin, $exists, $type, $size, $not or $elemMatch",
+                        op
+                    ),
+                )
+            }
+        });
+    }
+    Ok(qps)
+}
+
+// Converts a JSON value into a value that can be compared with the
+// index. Only primitive values are indexed.
+fn parse_value(v: &Value, at: &str) -> Result<TaggableValue, SelectorError> {
+    match v {
+        Value::Null => Ok(TaggableValue::Null),
+        Value::Bool(b) => Ok(TaggableValue::from(*b)),
+        Value::Number(n) => match n.as_f64() {
+            Some(n) => Ok(TaggableValue::from(n)),
+            None => err(at, format!("number {} can't be represented", n)),
+        },
+        Value::String(s) => Ok(TaggableValue::from(s.as_str())),
+        v => err(
+            at,
+            format!(
+                "expected a string, number, boolean or null, found {}",
+                describe(v)
+            ),
+        ),
+    }
+}
+
+fn parse_values(v: &Value, at: &str) -> Result<Vec<TaggableValue>, SelectorError> {
+    match v {
+        Value::Array(vs) => vs
+            .iter()
+            .enumerate()
+            .map(|(i, v)| parse_value(v, &location(at, &i.to_string())))
+            .collect(),
+        v => err(at, format!("expected an array, found {}", describe(v))),
+    }
+}
+
+// A single predicate that is the AND of qps.
+fn conjunction(mut qps: Vec<QP>) -> QP {
+    if qps.len() == 1 {
+        qps.remove(0)
+    } else {
+        QP::And(qps)
+    }
+}
+
+fn location(at: &str, k: &str) -> String {
+    if at.is_empty() {
+        k.to_string()
+    } else {
+        format!("{}.{}", at, k)
+    }
+}
+
+fn describe(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(a) if a.is_empty() => "an empty array",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::keypath;
+    use crate::query::tv;
+
+    #[test]
+    fn test_parse_selector() {
+        let q = parse_selector(&json!({
+            "name": "john",
+            "age": {"$gt": 20, "$lt": 100},
+            "a.c": {"$lte": 2},
+            "pet.$[]": {"$in": ["cat", "dog"]},
+            "pet.0": {"$exists": true},
+        }))
+        .unwrap();
+        // serde_json objects are ordered by key
+        assert_eq!(
+            q,
+            vec![
+                QP::LTE {
+                    p: keypath!["a", "c"],
+                    v: tv(2)
+                },
+                QP::GT {
+                    p: keypath!["age"],
+                    v: tv(20)
+                },
+                QP::LT {
+                    p: keypath!["age"],
+                    v: tv(100)
+                },
+                QP::E {
+                    p: keypath!["name"],
+                    v: tv("john")
+                },
+                QP::In {
+                    p: keypath!["pet", TaggableValue::AnyIndex],
+                    vs: vec![tv("cat"), tv("dog")]
+                },
+                QP::Exists {
+                    p: keypath!["pet", 0]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_selector_operators() {
+        let q = parse_selector(&json!({
+            "$or": [{"name": "john"}, {"age": {"$ne": null, "$type": "number"}}],
+            "pet": {"$size": 2, "$not": {"$elemMatch": {"$eq": "cat"}}},
+            "a": {"$elemMatch": {"b": 1}},
+        }))
+        .unwrap();
+        assert_eq!(
+            q,
+            vec![
+                QP::Or(vec![
+                    QP::E {
+                        p: keypath!["name"],
+                        v: tv("john")
+                    },
+                    QP::And(vec![
+                        QP::Not(Box::new(QP::E {
+                            p: keypath!["age"],
+                            v: TaggableValue::Null
+                        })),
+                        QP::Type {
+                            p: keypath!["age"],
+                            t: JsonType::Number
+                        },
+                    ]),
+                ]),
+                QP::ElemMatch {
+                    p: keypath!["a"],
+                    qps: vec![QP::E {
+                        p: keypath!["b"],
+                        v: tv(1)
+                    }]
+                },
+                QP::Not(Box::new(QP::ElemMatch {
+                    p: keypath!["pet"],
+                    qps: vec![QP::E {
+                        p: keypath![],
+                        v: tv("cat")
+                    }]
+                })),
+                QP::Size {
+                    p: keypath!["pet"],
+                    op: Cmp::E,
+                    n: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_selector_errors() {
+        let e = |s: Value| parse_selector(&s).unwrap_err().to_string();
+        assert_eq!(
+            e(json!(["name"])),
+            "invalid selector: expected an object, found an array"
+        );
+        assert_eq!(
+            e(json!({"age": {"$gt": [1]}})),
+            "invalid selector at `age.$gt`: expected a string, number, boolean or null, found an array"
+        );
+        assert_eq!(
+            e(json!({"$or": [{"age": {"$regex": "a"}}]})),
+            "invalid selector at `$or.0.age.$regex`: unsupported operator `$regex`; expected one of \
+             $eq, $ne, $gt, $gte, $lt, $lte, $in, $nin, $exists, $type, $size, $not or $elemMatch"
+        );
+        assert_eq!(
+            e(json!({"$where": "1"})),
+            "invalid selector at `$where`: unsupported operator `$where`; expected $and, $or or $nor"
+        );
+        assert_eq!(
+            e(json!({"a": {"b": 1}})),
+            "invalid selector at `a`: objects can't be compared by value; use dotted paths to select their fields"
+        );
+        assert_eq!(
+            e(json!({"a..b": 1})),
+            "invalid selector at `a..b`: field paths can't have empty components"
+        );
+        assert_eq!(
+            e(json!({"a": {"$in": [1, {}]}})),
+            "invalid selector at `a.$in.1`: expected a string, number, boolean or null, found an object"
+        );
+        assert_eq!(
+            e(json!({"a": {"$size": -1}})),
+            "invalid selector at `a.$size`: expected a non-negative integer, found -1"
+        );
+        assert_eq!(
+            e(json!({})),
+            "invalid selector: expected at least one field or operator, found an empty object"
+        );
+        assert_eq!(
+            e(json!({"$or": [{"a": 1}, {}]})),
+            "invalid selector at `$or.1`: expected at least one field or operator, found an empty object"
+        );
+        assert_eq!(
+            e(json!({"$or": []})),
+            "invalid selector at `$or`: expected a non-empty array of selectors, found an empty array"
+        );
+        assert_eq!(
+            e(json!({"a": {"$gt": 1, "b": 2}})),
+            "invalid selector at `a.b`: operators can't be mixed with fields"
+        );
+    }
+}
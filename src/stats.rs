@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+
+use sled::Db;
+
+use crate::{
+    docdb::DocDbError,
+    encoding,
+    query::{TaggableValue, QP},
+};
+
+// Query planning orders the predicates of an AND by an estimate of
+// how many results each has, using counts of the index keys with each
+// path, and with each path and value. docs/001 suggests HyperLogLog
+// sketches, but documents can't be removed from a sketch, so we keep
+// counts instead. Every write changes the counts of paths shared by
+// many documents, such as `name`, so they're kept out of the write's
+// transaction, where they would make concurrent writes conflict and be
+// retried. Instead they're updated once it commits, each with an
+// atomic read-modify-write, so concurrent writes don't lose updates.
+// A crash in between loses a write's changes, so the counts can drift
+// from the index; they only order predicates, so that's tolerated, and
+// docdb::reindex rebuilds them. Databases written before the counts
+// were kept also get them from docdb::reindex.
+
+// Deltas are the changes to the counts from writing a document.
+#[derive(Default)]
+pub(crate) struct Deltas(BTreeMap<Vec<u8>, i64>);
+
+impl Deltas {
+    // Records that an index key for path and v was added, when n is
+    // 1, or removed, when n is -1.
+    pub(crate) fn add(&mut self, path: &Vec<TaggableValue>, v: &TaggableValue, n: i64) {
+        *self
+            .0
+            .entry(encoding::encode_value_stats_key(path, v))
+            .or_default() += n;
+        *self
+            .0
+            .entry(encoding::encode_path_stats_key(path))
+            .or_default() += n;
+    }
+}
+
+// Applies deltas to the counts, after the write's transaction has
+// committed. Keys added and removed by the same write cancel out, so
+// aren't written. Counts that reach zero are removed.
+pub(crate) fn apply(db: &Db, deltas: Deltas) -> Result<(), DocDbError> {
+    for (k, n) in deltas.0 {
+        if n == 0 {
+            continue;
+        }
+        db.update_and_fetch(k, |old| {
+            let n = old.map_or(0, decode_i64) + n;
+            (n > 0).then(|| n.to_be_bytes().to_vec())
+        })?;
+    }
+    Ok(())
+}
+
+fn decode_i64(v: &[u8]) -> i64 {
+    v.try_into().map(i64::from_be_bytes).unwrap_or(0)
+}
+
+fn count(db: &Db, k: Vec<u8>) -> Result<u64, DocDbError> {
+    Ok(db.get(k)?.map_or(0, |v| decode_i64(&v).max(0) as u64))
+}
+
+// The number of index keys for path p and value v.
+fn value_count(db: &Db, p: &Vec<TaggableValue>, v: &TaggableValue) -> Result<u64, DocDbError> {
+    count(db, encoding::encode_value_stats_key(p, v))
+}
+
+// The number of index keys with path p.
+fn path_count(db: &Db, p: &Vec<TaggableValue>) -> Result<u64, DocDbError> {
+    count(db, encoding::encode_path_stats_key(p))
+}
+
+// The number of index keys with path p or a path below it, for
+// predicates that match objects and arrays at p.
+fn subtree_count(db: &Db, p: &Vec<TaggableValue>) -> Result<u64, DocDbError> {
+    let start_key = encoding::encode_path_stats_key(p);
+    let mut end_key = start_key.clone();
+    end_key.push(0x01);
+    let mut n = 0u64;
+    for i in db.range(start_key..end_key) {
+        let (_, v) = i?;
+        n = n.saturating_add(decode_i64(&v).max(0) as u64);
+    }
+    Ok(n)
+}
+
+// Estimates the number of results of qp. Not and Missing estimate
+// u64::MAX, so they are evaluated last, when they only need to remove
+// IDs from those already found.
+pub(crate) fn estimate(db: &Db, qp: &QP) -> Result<u64, DocDbError> {
+    let sum = |qps: &[QP]| -> Result<u64, DocDbError> {
+        let mut n = 0u64;
+        for qp in qps {
+            n = n.saturating_add(estimate(db, qp)?);
+        }
+        Ok(n)
+    };
+    let values = |p: &Vec<TaggableValue>, vs: &[TaggableValue]| -> Result<u64, DocDbError> {
+        let mut n = 0u64;
+        for v in vs {
+            n = n.saturating_add(value_count(db, p, v)?);
+        }
+        Ok(n)
+    };
+    match qp {
+        QP::E { p, v } => value_count(db, p, v),
+        QP::In { p, vs } => values(p, vs),
+        QP::NE { p, v } => Ok(path_count(db, p)?.saturating_sub(value_count(db, p, v)?)),
+        QP::NotIn { p, vs } => Ok(path_count(db, p)?.saturating_sub(values(p, vs)?)),
+        // We don't know how values are distributed, so assume
+        // ranges match every value at p.
+        QP::GT { p, .. }
+        | QP::GTE { p, .. }
+        | QP::LT { p, .. }
+        | QP::LTE { p, .. }
+        | QP::Prefix { p, .. }
+        | QP::Type { p, .. }
+        | QP::Match { p, .. } => path_count(db, p),
+        QP::Exists { p } | QP::Size { p, .. } | QP::ElemMatch { p, .. } => subtree_count(db, p),
+        QP::And(qps) => {
+            let mut n = None;
+            for qp in qps {
+                let m = estimate(db, qp)?;
+                n = Some(n.map_or(m, |n: u64| n.min(m)));
+            }
+            Ok(n.unwrap_or(0))
+        }
+        QP::Or(qps) => sum(qps),
+        QP::Missing { .. } | QP::Not(_) => Ok(u64::MAX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        docdb, keypath, parser,
+        query::{self, tv},
+    };
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn counts_test() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = docdb::new_database(tmp_dir.path()).unwrap();
+        docdb::set_document(&db, "doc1", json!({"name": "mike", "a": {"b": 1}}))?;
+        docdb::set_document(&db, "doc2", json!({"name": "john", "a": {"c": 2}}))?;
+        docdb::set_document(&db, "doc3", json!({"name": "john"}))?;
+        assert_eq!(3, path_count(&db, &keypath!["name"])?);
+        assert_eq!(2, value_count(&db, &keypath!["name"], &tv("john"))?);
+        assert_eq!(0, path_count(&db, &keypath!["a"])?);
+        assert_eq!(2, subtree_count(&db, &keypath!["a"])?);
+
+        // Updates and deletes remove the old values' counts
+        docdb::set_document(&db, "doc2", json!({"name": "fred", "a": {"c": 2}}))?;
+        docdb::delete_document(&db, "doc3")?;
+        assert_eq!(2, path_count(&db, &keypath!["name"])?);
+        assert_eq!(0, value_count(&db, &keypath!["name"], &tv("john"))?);
+        assert_eq!(1, value_count(&db, &keypath!["name"], &tv("fred"))?);
+        assert_eq!(1, value_count(&db, &keypath!["a", "c"], &tv(2))?);
+        // Counts that reach zero are removed
+        assert!(db
+            .get(encoding::encode_value_stats_key(
+                &keypath!["name"],
+                &tv("john")
+            ))?
+            .is_none());
+
+        let qp = QP::Or(vec![
+            QP::E {
+                p: keypath!["name"],
+                v: tv("mike"),
+            },
+            QP::Exists { p: keypath!["a"] },
+        ]);
+        assert_eq!(3, estimate(&db, &qp)?);
+        let qp = QP::Not(Box::new(qp));
+        assert_eq!(u64::MAX, estimate(&db, &qp)?);
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_counts_test() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = docdb::new_database(tmp_dir.path()).unwrap();
+
+        // Writes to different documents don't lose each other's
+        // changes to the counts of the paths they share
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let db = &db;
+                s.spawn(move || {
+                    for i in 0..25 {
+                        let docid = format!("doc{}-{}", t, i);
+                        docdb::set_document(db, &docid, json!({"name": "mike"})).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(100, path_count(&db, &keypath!["name"])?);
+        assert_eq!(100, value_count(&db, &keypath!["name"], &tv("mike"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn reindex_test() -> Result<(), DocDbError> {
+        let tmp_dir = tempdir().unwrap();
+        let db = docdb::new_database(tmp_dir.path()).unwrap();
+        docdb::set_document(&db, "doc1", json!({"name": "mike", "pet": ["cat", "cat"]}))?;
+        docdb::set_document(&db, "doc2", json!({"name": "john"}))?;
+
+        // As for a database written before the counts were kept
+        for prefix in [encoding::KEY_STATS_PATH, encoding::KEY_STATS_VALUE] {
+            for k in db.range(vec![prefix]..vec![prefix + 1]).keys() {
+                db.remove(k?)?;
+            }
+        }
+        assert_eq!(0, path_count(&db, &keypath!["name"])?);
+
+        docdb::reindex(&db)?;
+        assert_eq!(2, path_count(&db, &keypath!["name"])?);
+        assert_eq!(1, value_count(&db, &keypath!["name"], &tv("john"))?);
+        // The array's repeated value has one index key, so one count
+        let pets = keypath!["pet", TaggableValue::AnyIndex];
+        assert_eq!(1, value_count(&db, &pets, &tv("cat"))?);
+        assert_eq!(1, path_count(&db, &pets)?);
+        let r = query::search_index(&db, parser::parse("pet[*] = \"cat\"").unwrap())?;
+        assert_eq!(vec!["doc1".to_string()], r.results);
+
+        // Writes after reindexing keep the counts up to date
+        docdb::delete_document(&db, "doc1")?;
+        assert_eq!(1, path_count(&db, &keypath!["name"])?);
+        assert_eq!(0, value_count(&db, &pets, &tv("cat"))?);
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashSet};
+
+use rust_stemmers::{Algorithm, Stemmer};
+use serde_json::Value;
+
+use crate::{
+    docdb::DocDbError,
+    encoding::{self},
+    pathvalues::get_indexed_path_values,
+    query::{Ctx, TaggableValue},
+};
+
+// The full-text index is opt-in: only the string fields listed in a
+// TextIndex passed to docdb::set_document_with_options are analysed
+// into terms. For each field and term we write a posting key holding
+// the term frequency, and for each field a length key holding the
+// number of terms in the field. These are what we need to rank results
+// with BM25 (https://en.wikipedia.org/wiki/Okapi_BM25).
+
+// Analyzer turns text into the terms stored in, and searched for in,
+// the full-text index. A field should be searched using the same
+// analyzer it was indexed with.
+pub trait Analyzer: Send + Sync {
+    fn analyze(&self, text: &str) -> Vec<String>;
+}
+
+// StandardAnalyzer splits text on anything that isn't alphanumeric,
+// lowercases the words, removes stop words and then stems the rest.
+pub struct StandardAnalyzer {
+    stop_words: HashSet<String>,
+    stemmer: Option<Stemmer>,
+}
+
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+impl StandardAnalyzer {
+    pub fn new(stop_words: &[&str], stem: bool) -> Self {
+        StandardAnalyzer {
+            stop_words: stop_words.iter().map(|w| w.to_lowercase()).collect(),
+            stemmer: stem.then(|| Stemmer::create(Algorithm::English)),
+        }
+    }
+}
+
+impl Default for StandardAnalyzer {
+    // English stop words and stemming
+    fn default() -> Self {
+        StandardAnalyzer::new(ENGLISH_STOP_WORDS, true)
+    }
+}
+
+impl Analyzer for StandardAnalyzer {
+    fn analyze(&self, text: &str) -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_lowercase())
+            .filter(|w| !self.stop_words.contains(w))
+            .map(|w| match &self.stemmer {
+                Some(stemmer) => stemmer.stem(&w).into_owned(),
+                None => w,
+            })
+            .collect()
+    }
+}
+
+// TextIndex lists the string fields to add to the full-text index,
+// and the analyzer used to split them into terms.
+pub struct TextIndex {
+    pub paths: Vec<Vec<TaggableValue>>,
+    pub analyzer: Box<dyn Analyzer>,
+}
+
+impl TextIndex {
+    // Creates a TextIndex for paths using the StandardAnalyzer
+    pub fn new(paths: Vec<Vec<TaggableValue>>) -> Self {
+        TextIndex {
+            paths,
+            analyzer: Box::new(StandardAnalyzer::default()),
+        }
+    }
+}
+
+// Adds commands to add the text fields of `v` to the full-text index to
+// a batch. The keys written are also recorded, so delete_batch can remove
+// them without knowing which fields were indexed or how.
+pub(crate) fn insert_batch(
+    batch: &mut sled::Batch,
+    docid: &str,
+    v: &Value,
+    text: &TextIndex,
+) -> Result<(), DocDbError> {
+    let path_values = get_indexed_path_values(v.clone());
+    let mut written: Vec<Vec<u8>> = vec![];
+
+    for path in &text.paths {
+        let encoded_path = encoding::encode_path(path);
+        let mut tfs: BTreeMap<String, u32> = BTreeMap::new();
+        let mut length = 0u32;
+        for (p, v) in &path_values {
+            match v {
+                TaggableValue::String(s) if encoding::encode_path(p) == encoded_path => {
+                    for term in text.analyzer.analyze(s) {
+                        *tfs.entry(term).or_default() += 1;
+                        length += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if length == 0 {
+            continue;
+        }
+
+        for (term, tf) in tfs {
+            let k = encoding::encode_text_posting_key(docid, path, &term);
+            batch.insert(k.clone(), &tf.to_be_bytes());
+            written.push(k);
+        }
+        let k = encoding::encode_text_length_key(docid, path);
+        batch.insert(k.clone(), &length.to_be_bytes());
+        written.push(k);
+    }
+
+    if !written.is_empty() {
+        let buf = rmp_serde::to_vec(&written)?;
+        batch.insert(encoding::encode_text_document_key(docid), buf);
+    }
+    Ok(())
+}
+
+// Adds commands to remove docid from the full-text index to a batch,
+// given the value of its text document key, which lists its keys.
+pub(crate) fn delete_batch(
+    batch: &mut sled::Batch,
+    docid: &str,
+    packed: Option<&[u8]>,
+) -> Result<(), DocDbError> {
+    if let Some(packed) = packed {
+        let written = rmp_serde::from_slice::<Vec<Vec<u8>>>(packed)?;
+        for k in written {
+            batch.remove(k);
+        }
+        batch.remove(encoding::encode_text_document_key(docid));
+    }
+    Ok(())
+}
+
+// BM25 parameters, using common defaults.
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+// Returns the BM25 score of every document whose text at path contains
+// any of the terms of q. The keys read count towards the query's limits.
+pub(crate) fn search(
+    ctx: &mut Ctx,
+    path: &Vec<TaggableValue>,
+    q: &str,
+    analyzer: &dyn Analyzer,
+) -> Result<BTreeMap<String, f64>, DocDbError> {
+    let db = ctx.db;
+    let mut terms = analyzer.analyze(q);
+    terms.sort();
+    terms.dedup();
+
+    // The number of documents and average length of the field
+    let mut n_docs = 0u64;
+    let mut total_length = 0u64;
+    let start_key = encoding::text_length_query_lower_bound(path);
+    let end_key = encoding::text_length_query_upper_bound(path);
+    let scan = ctx.stats.start_scan();
+    for i in db.range(start_key..end_key) {
+        let (k, v) = i?;
+        ctx.read_key(scan, &k, &v)?;
+        n_docs += 1;
+        total_length += decode_u32(&v) as u64;
+    }
+    if n_docs == 0 {
+        return Ok(BTreeMap::new());
+    }
+    let avg_length = total_length as f64 / n_docs as f64;
+
+    let mut scores = BTreeMap::new();
+    for term in terms {
+        let start_key = encoding::text_posting_query_lower_bound(path, &term);
+        let end_key = encoding::text_posting_query_upper_bound(path, &term);
+        let scan = ctx.stats.start_scan();
+        let mut postings = vec![];
+        for i in db.range(start_key..end_key) {
+            let (k, v) = i?;
+            ctx.read_key(scan, &k, &v)?;
+            match encoding::decode_index_key_docid(&k) {
+                Ok(docid) => postings.push((docid.to_string(), decode_u32(&v) as f64)),
+                Err(_) => ctx.stats.decode_failures += 1,
+            };
+        }
+
+        let df = postings.len() as f64;
+        let idf = (1.0 + (n_docs as f64 - df + 0.5) / (df + 0.5)).ln();
+        for (docid, tf) in postings {
+            let length = match db.get(encoding::encode_text_length_key(&docid, path))? {
+                Some(v) => decode_u32(&v) as f64,
+                None => avg_length,
+            };
+            let norm = 1.0 - BM25_B + BM25_B * length / avg_length;
+            *scores.entry(docid).or_insert(0.0) +=
+                idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * norm);
+        }
+    }
+    Ok(scores)
+}
+
+fn decode_u32(v: &[u8]) -> u32 {
+    v.try_into().map(u32::from_be_bytes).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standard_analyzer() {
+        let a = StandardAnalyzer::default();
+        assert_eq!(
+            a.analyze("The Quick brown foxes, jumping over the lazy dogs!"),
+            vec!["quick", "brown", "fox", "jump", "over", "lazi", "dog"]
+        );
+        let a = StandardAnalyzer::new(&["over"], false);
+        assert_eq!(
+            a.analyze("The foxes jumping over"),
+            vec!["the", "foxes", "jumping"]
+        );
+    }
+}
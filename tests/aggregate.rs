@@ -0,0 +1,182 @@
+use rust_docdb::aggregate::{self, Agg};
+use rust_docdb::docdb;
+use rust_docdb::docdb::DocDbError;
+use rust_docdb::keypath;
+use rust_docdb::query;
+use rust_docdb::query::tv;
+use rust_docdb::query::TaggableValue;
+use serde_json::json;
+use sled::Db;
+use tempfile::tempdir;
+
+fn insert_orders(db: &Db) -> Result<(), DocDbError> {
+    let orders = [
+        json!({"status": "open", "total": 10, "items": [{"qty": 1}, {"qty": 2}]}),
+        json!({"status": "open", "total": 30, "items": [{"qty": 5}]}),
+        json!({"status": "shipped", "total": 25.5, "items": []}),
+        json!({"status": "shipped", "total": "unknown"}),
+        json!({"total": 100}),
+    ];
+    for (i, order) in orders.into_iter().enumerate() {
+        docdb::set_document(db, &format!("order{}", i), order)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn aggregate_group_by() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_orders(&db)?;
+
+    let r = aggregate::aggregate(
+        &db,
+        vec![],
+        Some(&keypath!["status"]),
+        &[
+            Agg::Count,
+            Agg::Sum(keypath!["total"]),
+            Agg::Avg(keypath!["total"]),
+            Agg::Min(keypath!["total"]),
+            Agg::Max(keypath!["total"]),
+        ],
+    )?;
+    let groups: Vec<_> = r.groups.into_iter().map(|g| (g.key, g.values)).collect();
+    assert_eq!(
+        vec![
+            (
+                Some(tv("open")),
+                vec![Some(2.0), Some(40.0), Some(20.0), Some(10.0), Some(30.0)]
+            ),
+            // Values that aren't numbers are ignored
+            (
+                Some(tv("shipped")),
+                vec![Some(2.0), Some(25.5), Some(25.5), Some(25.5), Some(25.5)]
+            ),
+            (
+                None,
+                vec![
+                    Some(1.0),
+                    Some(100.0),
+                    Some(100.0),
+                    Some(100.0),
+                    Some(100.0)
+                ]
+            ),
+        ],
+        groups
+    );
+    // One scan each for the documents, status and total
+    assert_eq!(3, r.stats.scans);
+
+    Ok(())
+}
+
+#[test]
+fn aggregate_filtered() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_orders(&db)?;
+
+    // Without group by, there's a single group, even when empty
+    let r = aggregate::aggregate(
+        &db,
+        vec![query::QP::E {
+            p: keypath!["status"],
+            v: tv("open"),
+        }],
+        None,
+        &[
+            Agg::Count,
+            Agg::Sum(keypath!["items", TaggableValue::AnyIndex, "qty"]),
+            Agg::Max(keypath!["items", TaggableValue::AnyIndex, "qty"]),
+        ],
+    )?;
+    assert_eq!(1, r.groups.len());
+    assert_eq!(None, r.groups[0].key);
+    assert_eq!(vec![Some(2.0), Some(8.0), Some(5.0)], r.groups[0].values);
+
+    let r = aggregate::aggregate(
+        &db,
+        vec![query::QP::E {
+            p: keypath!["status"],
+            v: tv("cancelled"),
+        }],
+        None,
+        &[
+            Agg::Count,
+            Agg::Sum(keypath!["total"]),
+            Agg::Avg(keypath!["total"]),
+        ],
+    )?;
+    assert_eq!(vec![Some(0.0), Some(0.0), None], r.groups[0].values);
+
+    // Equal elements of a document are each in the sum
+    docdb::set_document(
+        &db,
+        "order5",
+        json!({"status": "returned", "items": [{"qty": 10}, {"qty": 10}]}),
+    )?;
+    let r = aggregate::aggregate(
+        &db,
+        vec![query::QP::E {
+            p: keypath!["status"],
+            v: tv("returned"),
+        }],
+        None,
+        &[
+            Agg::Sum(keypath!["items", TaggableValue::AnyIndex, "qty"]),
+            Agg::Avg(keypath!["items", TaggableValue::AnyIndex, "qty"]),
+            Agg::Max(keypath!["items", TaggableValue::AnyIndex, "qty"]),
+        ],
+    )?;
+    assert_eq!(vec![Some(20.0), Some(10.0), Some(10.0)], r.groups[0].values);
+
+    Ok(())
+}
+
+#[test]
+fn distinct_values() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_orders(&db)?;
+
+    let r = aggregate::distinct(&db, &keypath!["status"], None)?;
+    assert_eq!(vec![(tv("open"), 2), (tv("shipped"), 2)], r.values);
+    assert_eq!(1, r.stats.scans);
+
+    // Values of all types are in index order
+    let r = aggregate::distinct(&db, &keypath!["total"], None)?;
+    assert_eq!(
+        vec![
+            (tv(10), 1),
+            (tv(25.5), 1),
+            (tv(30), 1),
+            (tv(100), 1),
+            (tv("unknown"), 1)
+        ],
+        r.values
+    );
+
+    let r = aggregate::distinct(
+        &db,
+        &keypath!["items", TaggableValue::AnyIndex, "qty"],
+        Some(vec![query::QP::E {
+            p: keypath!["status"],
+            v: tv("open"),
+        }]),
+    )?;
+    assert_eq!(vec![(tv(1), 1), (tv(2), 1), (tv(5), 1)], r.values);
+
+    let r = aggregate::distinct(
+        &db,
+        &keypath!["status"],
+        Some(vec![query::QP::LT {
+            p: keypath!["total"],
+            v: tv(27),
+        }]),
+    )?;
+    assert_eq!(vec![(tv("open"), 1), (tv("shipped"), 1)], r.values);
+
+    Ok(())
+}
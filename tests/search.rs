@@ -3,25 +3,24 @@ use rust_docdb::docdb::DocDbError;
 use rust_docdb::keypath;
 use rust_docdb::query;
 use rust_docdb::query::tv;
+use rust_docdb::query::Cmp;
+use rust_docdb::query::JsonType;
 use rust_docdb::query::TaggableValue;
+use rust_docdb::selector;
+use rust_docdb::text;
 use serde_json::json;
-use sled;
 use sled::Db;
 use tempfile::tempdir;
 
 fn insert_test_data(db: &Db) -> Result<(), DocDbError> {
     docdb::set_document(
-        &db,
+        db,
         "doc1",
         json!({"a":{"b": 1}, "name": "mike", "age": 40, "pet": ["cat", "cat", "dog"]}),
     )?;
+    docdb::set_document(db, "doc2", json!({"a":{"c": 2}, "name": "john", "age": 24}))?;
     docdb::set_document(
-        &db,
-        "doc2",
-        json!({"a":{"c": 2}, "name": "john", "age": 24}),
-    )?;
-    docdb::set_document(
-        &db,
+        db,
         "doc3",
         json!({"a":{"c": 2}, "name": "john", "age": 110, "pet": ["wombat"]}),
     )?;
@@ -81,6 +80,49 @@ fn query_eq() -> Result<(), DocDbError> {
     )?;
     assert_eq!(Vec::<String>::new(), ids.results);
 
+    // doc1's `a` is an object with a field b, which is not the value
+    // "b", whether the predicate is evaluated alone or within an AND
+    let a_eq_b = || query::QP::E {
+        p: keypath!["a"],
+        v: tv("b"),
+    };
+    let ids = query::search_index(&db, vec![a_eq_b()])?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![
+            a_eq_b(),
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("mike"),
+            },
+        ],
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![
+            a_eq_b(),
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(30),
+            },
+        ],
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+    // Exists does match objects with a field
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Exists { p: keypath!["a"] },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("mike"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+
     Ok(())
 }
 
@@ -321,3 +363,2201 @@ fn query_search_short_circuit_empty_scan() -> Result<(), DocDbError> {
     assert_eq!(1, ids.stats.scans, "index scans not short circuited");
     Ok(())
 }
+
+#[test]
+fn query_or() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Or(vec![
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("mike"),
+            },
+        ])],
+    )?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc2".to_string(), "doc3".to_string()],
+        ids.results
+    );
+    assert_eq!(2, ids.stats.scans);
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Or(vec![
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("notaname"),
+            },
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(100),
+            },
+        ])],
+    )?;
+    assert_eq!(vec!["doc3".to_string()], ids.results);
+    Ok(())
+}
+
+#[test]
+fn query_not() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    // NOT on its own is evaluated against every document
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Not(Box::new(query::QP::E {
+            p: keypath!["name"],
+            v: tv("john"),
+        }))],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    // Within an AND, NOT removes IDs from the existing result set
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Not(Box::new(query::QP::E {
+                p: keypath!["pet", 0],
+                v: tv("wombat"),
+            })),
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(20),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc2".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+    Ok(())
+}
+
+#[test]
+fn query_nested_boolean() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    // (name = mike OR (name = john AND age < 50)) AND NOT a.b = 1
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Or(vec![
+                query::QP::E {
+                    p: keypath!["name"],
+                    v: tv("mike"),
+                },
+                query::QP::And(vec![
+                    query::QP::E {
+                        p: keypath!["name"],
+                        v: tv("john"),
+                    },
+                    query::QP::LT {
+                        p: keypath!["age"],
+                        v: tv(50),
+                    },
+                ]),
+            ]),
+            query::QP::Not(Box::new(query::QP::E {
+                p: keypath!["a", "b"],
+                v: tv(1),
+            })),
+        ],
+    )?;
+    assert_eq!(vec!["doc2".to_string()], ids.results);
+
+    // An empty AND branch inside an OR short circuits only that branch
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Or(vec![
+            query::QP::And(vec![
+                query::QP::E {
+                    p: keypath!["name"],
+                    v: tv("notaname"),
+                },
+                query::QP::LTE {
+                    p: keypath!["age"],
+                    v: tv(40),
+                },
+            ]),
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("mike"),
+            },
+        ])],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans, "AND branch not short circuited");
+    Ok(())
+}
+
+#[test]
+fn query_collapse_ranges() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    // age > 12 AND age >= 15 AND age < 33 => age >= 15 AND age < 33
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(12),
+            },
+            query::QP::GTE {
+                p: keypath!["age"],
+                v: tv(15),
+            },
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(33),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc2".to_string()], ids.results);
+    assert_eq!(1, ids.stats.scans, "age predicates not collapsed");
+
+    // Predicates on different fields are not collapsed together
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(12),
+            },
+            query::QP::LTE {
+                p: keypath!["age"],
+                v: tv(40),
+            },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("mike"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    // Equality narrows the range to a single value
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::E {
+                p: keypath!["age"],
+                v: tv(24),
+            },
+            query::QP::GTE {
+                p: keypath!["age"],
+                v: tv(24),
+            },
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(50),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc2".to_string()], ids.results);
+    assert_eq!(1, ids.stats.scans);
+
+    Ok(())
+}
+
+#[test]
+fn query_collapse_ranges_no_overlap() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    // age > 12 AND age >= 15 AND age < 5
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(12),
+            },
+            query::QP::GTE {
+                p: keypath!["age"],
+                v: tv(15),
+            },
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(5),
+            },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+        ],
+    )?;
+    assert_eq!(0, ids.results.len(), "wrong result count");
+    assert_eq!(0, ids.stats.scans, "non-overlapping ranges were scanned");
+
+    // age = 24 AND age > 24
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::E {
+                p: keypath!["age"],
+                v: tv(24),
+            },
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(24),
+            },
+        ],
+    )?;
+    assert_eq!(0, ids.results.len(), "wrong result count");
+    assert_eq!(0, ids.stats.scans, "non-overlapping ranges were scanned");
+
+    Ok(())
+}
+
+#[test]
+fn query_any_array_element() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::E {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            v: tv("dog"),
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+
+    // doc1 has two cats, but is only returned once
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::GTE {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            v: tv("cat"),
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+
+    docdb::set_document(
+        &db,
+        "orders1",
+        json!({"orders": [{"total": 50}, {"total": 150}, {"total": 250}]}),
+    )?;
+    docdb::set_document(
+        &db,
+        "orders2",
+        json!({"orders": [{"total": 20}, {"total": 30}]}),
+    )?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::GT {
+            p: keypath!["orders", TaggableValue::AnyIndex, "total"],
+            v: tv(100),
+        }],
+    )?;
+    assert_eq!(vec!["orders1".to_string()], ids.results);
+
+    // Replacing the document removes its old array entries
+    docdb::set_document(&db, "orders1", json!({"orders": [{"total": 5}]}))?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::GT {
+            p: keypath!["orders", TaggableValue::AnyIndex, "total"],
+            v: tv(100),
+        }],
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+
+    // Paths with both array indexes and AnyIndex aren't indexed
+    docdb::set_document(&db, "orders3", json!({"orders": [{"items": ["x", "y"]}]}))?;
+    let r = query::search_index(
+        &db,
+        vec![query::QP::E {
+            p: keypath!["orders", 0, "items", TaggableValue::AnyIndex],
+            v: tv("x"),
+        }],
+    );
+    assert!(matches!(r, Err(DocDbError::InvalidQuery(_))));
+    let r = query::search_index(
+        &db,
+        vec![query::QP::Not(Box::new(query::QP::Exists {
+            p: keypath!["orders", TaggableValue::AnyIndex, "items", 1],
+        }))],
+    );
+    assert!(matches!(r, Err(DocDbError::InvalidQuery(_))));
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::E {
+            p: keypath![
+                "orders",
+                TaggableValue::AnyIndex,
+                "items",
+                TaggableValue::AnyIndex
+            ],
+            v: tv("x"),
+        }],
+    )?;
+    assert_eq!(vec!["orders3".to_string()], ids.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_any_array_element_not_collapsed() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    docdb::set_document(&db, "doc1", json!({"pet": ["ant", "zebra"]}))?;
+
+    // Each predicate is satisfied by a different element
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::GT {
+                p: keypath!["pet", TaggableValue::AnyIndex],
+                v: tv("b"),
+            },
+            query::QP::LT {
+                p: keypath!["pet", TaggableValue::AnyIndex],
+                v: tv("c"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    Ok(())
+}
+
+#[test]
+fn query_exists_missing() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Exists {
+            p: keypath!["a", "b"],
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+
+    let ids = query::search_index(&db, vec![query::QP::Missing { p: keypath!["pet"] }])?;
+    assert_eq!(vec!["doc2".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    // Within an AND, Missing narrows the existing result set
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Missing {
+                p: keypath!["a", "b"],
+            },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+            query::QP::Exists { p: keypath!["pet"] },
+        ],
+    )?;
+    assert_eq!(vec!["doc3".to_string()], ids.results);
+    assert_eq!(3, ids.stats.scans);
+
+    // Exists collapses with range predicates on the same field
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Exists { p: keypath!["age"] },
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(30),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+    assert_eq!(1, ids.stats.scans);
+
+    // An empty array or object has no index keys, so counts as missing
+    docdb::set_document(&db, "doc4", json!({"a": []}))?;
+    docdb::set_document(&db, "doc5", json!({"a": {}}))?;
+    let ids = query::search_index(&db, vec![query::QP::Exists { p: keypath!["a"] }])?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc2".to_string(), "doc3".to_string()],
+        ids.results
+    );
+    let ids = query::search_index(&db, vec![query::QP::Missing { p: keypath!["a"] }])?;
+    assert_eq!(vec!["doc4".to_string(), "doc5".to_string()], ids.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_type() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(&db, "doc4", json!({"age": "unknown", "name": null}))?;
+    docdb::set_document(&db, "doc5", json!({"age": true}))?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Type {
+            p: keypath!["age"],
+            t: JsonType::Number,
+        }],
+    )?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc2".to_string(), "doc3".to_string()],
+        ids.results
+    );
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Type {
+            p: keypath!["age"],
+            t: JsonType::String,
+        }],
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Type {
+            p: keypath!["age"],
+            t: JsonType::Bool,
+        }],
+    )?;
+    assert_eq!(vec!["doc5".to_string()], ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Type {
+            p: keypath!["name"],
+            t: JsonType::Null,
+        }],
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+
+    // Type collapses with range predicates on the same field
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Type {
+                p: keypath!["age"],
+                t: JsonType::Number,
+            },
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv("a"),
+            },
+        ],
+    )?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc2".to_string(), "doc3".to_string()],
+        ids.results
+    );
+    assert_eq!(1, ids.stats.scans);
+
+    // Only values at exactly the path match, not the fields of an
+    // object or the elements of an array there
+    docdb::set_document(&db, "doc6", json!({"age": {"b": 1}}))?;
+    docdb::set_document(&db, "doc7", json!({"age": [5]}))?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Type {
+            p: keypath!["age"],
+            t: JsonType::String,
+        }],
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Type {
+            p: keypath!["age"],
+            t: JsonType::Number,
+        }],
+    )?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc2".to_string(), "doc3".to_string()],
+        ids.results
+    );
+
+    Ok(())
+}
+
+#[test]
+fn query_strict_types() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(&db, "doc4", json!({"age": "unknown"}))?;
+    let strict = query::QueryOptions {
+        strict_types: true,
+        ..Default::default()
+    };
+
+    // Without strict types, ranges cross JSON types
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::LT {
+            p: keypath!["age"],
+            v: tv("a"),
+        }],
+    )?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc2".to_string(), "doc3".to_string()],
+        ids.results
+    );
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::LT {
+            p: keypath!["age"],
+            v: tv("a"),
+        }],
+        &strict,
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::GT {
+            p: keypath!["age"],
+            v: tv("a"),
+        }],
+        &strict,
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::GTE {
+            p: keypath!["age"],
+            v: tv(false),
+        }],
+    )?;
+    assert_eq!(4, ids.results.len());
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::GTE {
+            p: keypath!["age"],
+            v: tv(false),
+        }],
+        &strict,
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+
+    // Collapsed ranges are also restricted
+    let ids = query::search_index_with_options(
+        &db,
+        vec![
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(30),
+            },
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(1000),
+            },
+        ],
+        &strict,
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+
+    // Only values at exactly the path match, not the fields of an
+    // object or the elements of an array there
+    docdb::set_document(&db, "doc5", json!({"age": {"b": 1}}))?;
+    docdb::set_document(&db, "doc6", json!({"age": [5]}))?;
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::GT {
+            p: keypath!["age"],
+            v: tv("a"),
+        }],
+        &strict,
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::LT {
+            p: keypath!["age"],
+            v: tv(30),
+        }],
+        &strict,
+    )?;
+    assert_eq!(vec!["doc2".to_string()], ids.results);
+    // Nor when the scan also orders the results
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::GT {
+            p: keypath!["age"],
+            v: tv("a"),
+        }],
+        &query::QueryOptions {
+            strict_types: true,
+            order_by: Some(query::OrderBy {
+                p: keypath!["age"],
+                direction: query::Direction::Asc,
+            }),
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_in() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::In {
+            p: keypath!["age"],
+            vs: vec![tv(110), tv(40), tv("forty"), tv(40)],
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+    // Duplicate values are only scanned once
+    assert_eq!(3, ids.stats.scans);
+
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::In {
+                p: keypath!["name"],
+                vs: vec![tv("john"), tv("mike")],
+            },
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(100),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc2".to_string()], ids.results);
+
+    // An IN matching nothing short circuits the AND
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(100),
+            },
+            query::QP::In {
+                p: keypath!["name"],
+                vs: vec![tv("notaname"), tv("other")],
+            },
+        ],
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::In {
+            p: keypath!["name"],
+            vs: vec![],
+        }],
+    )?;
+    assert_eq!(Vec::<String>::new(), ids.results);
+    assert_eq!(0, ids.stats.scans);
+
+    Ok(())
+}
+
+#[test]
+fn query_prefix() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(&db, "doc4", json!({"name": "jo"}))?;
+    docdb::set_document(&db, "doc5", json!({"name": "jp"}))?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Prefix {
+            p: keypath!["name"],
+            prefix: "jo".to_string(),
+        }],
+    )?;
+    assert_eq!(
+        vec!["doc2".to_string(), "doc3".to_string(), "doc4".to_string()],
+        ids.results
+    );
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Prefix {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            prefix: "wom".to_string(),
+        }],
+    )?;
+    assert_eq!(vec!["doc3".to_string()], ids.results);
+
+    // Prefix collapses with other predicates on the same field
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Prefix {
+                p: keypath!["name"],
+                prefix: "jo".to_string(),
+            },
+            query::QP::GT {
+                p: keypath!["name"],
+                v: tv("jo"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc2".to_string(), "doc3".to_string()], ids.results);
+    assert_eq!(1, ids.stats.scans);
+
+    // Field names below the path don't match
+    docdb::set_document(&db, "doc6", json!({"name": {"john": "x"}}))?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Prefix {
+            p: keypath!["name"],
+            prefix: "jo".to_string(),
+        }],
+    )?;
+    assert_eq!(
+        vec!["doc2".to_string(), "doc3".to_string(), "doc4".to_string()],
+        ids.results
+    );
+
+    Ok(())
+}
+
+#[test]
+fn query_ne_not_in() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(&db, "doc4", json!({"age": "forty"}))?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::NE {
+            p: keypath!["name"],
+            v: tv("john"),
+        }],
+    )?;
+    // doc4 has no name, so doesn't match
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    // Values of other types are not equal
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::NE {
+            p: keypath!["age"],
+            v: tv(24),
+        }],
+    )?;
+    assert_eq!(
+        vec!["doc1".to_string(), "doc3".to_string(), "doc4".to_string()],
+        ids.results
+    );
+
+    // Any element of an array that isn't cat matches
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::NE {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            v: tv("cat"),
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::NotIn {
+            p: keypath!["age"],
+            vs: vec![tv(110), tv("forty"), tv(24), tv(110)],
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+    // One scan either side of each distinct value
+    assert_eq!(4, ids.stats.scans);
+
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::NotIn {
+                p: keypath!["age"],
+                vs: vec![tv(110)],
+            },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc2".to_string()], ids.results);
+
+    // Arrays and objects at the path don't match, as they have no
+    // value there
+    docdb::set_document(&db, "doc5", json!({"pet": ["dog"]}))?;
+    docdb::set_document(&db, "doc6", json!({"pet": {"name": "rex"}}))?;
+    docdb::set_document(&db, "doc7", json!({"pet": "cat"}))?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::NE {
+            p: keypath!["pet"],
+            v: tv("dog"),
+        }],
+    )?;
+    assert_eq!(vec!["doc7".to_string()], ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::NotIn {
+            p: keypath!["pet"],
+            vs: vec![tv("dog"), tv("rex")],
+        }],
+    )?;
+    assert_eq!(vec!["doc7".to_string()], ids.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_elem_match() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(
+        &db,
+        "order1",
+        json!({"items": [{"sku": "A", "qty": 1}, {"sku": "B", "qty": 10}]}),
+    )?;
+    docdb::set_document(
+        &db,
+        "order2",
+        json!({"items": [{"sku": "A", "qty": 10, "gift": true}, {"sku": "C", "qty": 1}]}),
+    )?;
+
+    // Without ElemMatch, the conditions can hold for different items
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::E {
+                p: keypath!["items", TaggableValue::AnyIndex, "sku"],
+                v: tv("A"),
+            },
+            query::QP::GT {
+                p: keypath!["items", TaggableValue::AnyIndex, "qty"],
+                v: tv(5),
+            },
+        ],
+    )?;
+    assert_eq!(
+        vec!["order1".to_string(), "order2".to_string()],
+        ids.results
+    );
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["items"],
+            qps: vec![
+                query::QP::E {
+                    p: keypath!["sku"],
+                    v: tv("A"),
+                },
+                query::QP::GT {
+                    p: keypath!["qty"],
+                    v: tv(5),
+                },
+            ],
+        }],
+    )?;
+    assert_eq!(vec!["order2".to_string()], ids.results);
+    assert_eq!(1, ids.stats.scans);
+
+    // Boolean operators and Missing within an element
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["items"],
+            qps: vec![
+                query::QP::Or(vec![
+                    query::QP::E {
+                        p: keypath!["sku"],
+                        v: tv("A"),
+                    },
+                    query::QP::E {
+                        p: keypath!["sku"],
+                        v: tv("C"),
+                    },
+                ]),
+                query::QP::Missing {
+                    p: keypath!["gift"],
+                },
+            ],
+        }],
+    )?;
+    assert_eq!(
+        vec!["order1".to_string(), "order2".to_string()],
+        ids.results
+    );
+
+    // Arrays of values are matched using an empty path
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["pet"],
+            qps: vec![
+                query::QP::GT {
+                    p: keypath![],
+                    v: tv("cat"),
+                },
+                query::QP::LT {
+                    p: keypath![],
+                    v: tv("elephant"),
+                },
+            ],
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string()], ids.results);
+
+    // ElemMatch takes part in an AND like any other predicate
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::ElemMatch {
+                p: keypath!["pet"],
+                qps: vec![query::QP::Prefix {
+                    p: keypath![],
+                    prefix: "wom".to_string(),
+                }],
+            },
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc3".to_string()], ids.results);
+
+    // AnyIndex paths match the elements of arrays within an element
+    docdb::set_document(
+        &db,
+        "orders1",
+        json!({"orders": [
+            {"items": ["x", "y"], "total": 5},
+            {"items": ["y", "z"], "total": 20}
+        ]}),
+    )?;
+    docdb::set_document(
+        &db,
+        "orders2",
+        json!({"orders": [{"items": ["z"], "total": 5}, {"items": [], "total": 20}]}),
+    )?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["orders"],
+            qps: vec![
+                query::QP::E {
+                    p: keypath!["items", TaggableValue::AnyIndex],
+                    v: tv("z"),
+                },
+                query::QP::GT {
+                    p: keypath!["total"],
+                    v: tv(10),
+                },
+            ],
+        }],
+    )?;
+    assert_eq!(vec!["orders1".to_string()], ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["orders"],
+            qps: vec![
+                query::QP::Missing {
+                    p: keypath!["items", TaggableValue::AnyIndex],
+                },
+                query::QP::GT {
+                    p: keypath!["total"],
+                    v: tv(10),
+                },
+            ],
+        }],
+    )?;
+    assert_eq!(vec!["orders2".to_string()], ids.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_elem_match_invalid() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let result = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["pet"],
+            qps: vec![query::QP::ElemMatch {
+                p: keypath![],
+                qps: vec![],
+            }],
+        }],
+    );
+    assert!(matches!(result, Err(DocDbError::InvalidQuery(_))));
+
+    let result = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            qps: vec![],
+        }],
+    );
+    assert!(matches!(result, Err(DocDbError::InvalidQuery(_))));
+
+    Ok(())
+}
+
+#[test]
+fn query_size() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(&db, "doc4", json!({"pet": []}))?;
+    docdb::set_document(
+        &db,
+        "order1",
+        json!({"orders": [{"items": [1, 2, 3, 4]}, {"items": [1]}]}),
+    )?;
+    docdb::set_document(&db, "order2", json!({"orders": [{"items": [1, 2]}]}))?;
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Size {
+            p: keypath!["pet"],
+            op: Cmp::E,
+            n: 0,
+        }],
+    )?;
+    assert_eq!(vec!["doc4".to_string()], ids.results);
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Size {
+            p: keypath!["pet"],
+            op: Cmp::GTE,
+            n: 1,
+        }],
+    )?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Size {
+            p: keypath!["orders", TaggableValue::AnyIndex, "items"],
+            op: Cmp::GT,
+            n: 3,
+        }],
+    )?;
+    assert_eq!(vec!["order1".to_string()], ids.results);
+
+    // Sizes on a field collapse with each other, but not with values
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Size {
+                p: keypath!["pet"],
+                op: Cmp::GT,
+                n: 0,
+            },
+            query::QP::Size {
+                p: keypath!["pet"],
+                op: Cmp::LT,
+                n: 2,
+            },
+            query::QP::E {
+                p: keypath!["pet", 0],
+                v: tv("wombat"),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["doc3".to_string()], ids.results);
+    assert_eq!(2, ids.stats.scans);
+
+    // Replacing a document removes its old array sizes
+    docdb::set_document(&db, "doc4", json!({"pet": ["dog"]}))?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Size {
+            p: keypath!["pet"],
+            op: Cmp::LTE,
+            n: 1,
+        }],
+    )?;
+    assert_eq!(vec!["doc3".to_string(), "doc4".to_string()], ids.results);
+
+    Ok(())
+}
+
+fn insert_text_data(db: &Db) -> Result<(), DocDbError> {
+    let opts = docdb::WriteOptions {
+        text: Some(text::TextIndex::new(vec![keypath!["body"]])),
+    };
+    let docs = [
+        ("post1", "The cat sat on the mat"),
+        ("post2", "Cats chase dogs, and dogs chase cats"),
+        ("post3", "A dog barked at the postman"),
+        ("post4", "Nothing to see here"),
+    ];
+    for (i, (docid, body)) in docs.into_iter().enumerate() {
+        docdb::set_document_with_options(db, docid, json!({"body": body, "n": i}), &opts)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn query_match() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_text_data(&db)?;
+    // Documents written without a TextIndex aren't in the text index
+    docdb::set_document(&db, "post5", json!({"body": "cat"}))?;
+
+    // Stemming matches cat and cats, and post2 has more mentions
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Match {
+            p: keypath!["body"],
+            q: "Cats".to_string(),
+        }],
+    )?;
+    assert_eq!(vec!["post2".to_string(), "post1".to_string()], ids.results);
+
+    // Results match any term, ranked by combined score
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Match {
+            p: keypath!["body"],
+            q: "dog postman".to_string(),
+        }],
+    )?;
+    assert_eq!(vec!["post3".to_string(), "post2".to_string()], ids.results);
+
+    // Stop words aren't indexed
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Match {
+            p: keypath!["body"],
+            q: "the".to_string(),
+        }],
+    )?;
+    assert_eq!(0, ids.results.len());
+
+    // Match combines with other predicates
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Match {
+                p: keypath!["body"],
+                q: "cat".to_string(),
+            },
+            query::QP::LT {
+                p: keypath!["n"],
+                v: tv(1),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["post1".to_string()], ids.results);
+
+    // A custom analyzer can be used to search
+    let opts = query::QueryOptions {
+        analyzer: Some(Box::new(text::StandardAnalyzer::new(&[], false))),
+        ..Default::default()
+    };
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::Match {
+            p: keypath!["body"],
+            q: "cats".to_string(),
+        }],
+        &opts,
+    )?;
+    assert_eq!(0, ids.results.len());
+
+    Ok(())
+}
+
+#[test]
+fn query_match_updates() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_text_data(&db)?;
+    let cat = || {
+        vec![query::QP::Match {
+            p: keypath!["body"],
+            q: "cat".to_string(),
+        }]
+    };
+
+    // Replacing a document removes its old terms
+    let opts = docdb::WriteOptions {
+        text: Some(text::TextIndex::new(vec![keypath!["body"]])),
+    };
+    docdb::set_document_with_options(&db, "post1", json!({"body": "A quiet mouse"}), &opts)?;
+    let ids = query::search_index(&db, cat())?;
+    assert_eq!(vec!["post2".to_string()], ids.results);
+
+    // Updating a document without a TextIndex keeps its terms
+    docdb::set_document(&db, "post2", json!({"body": "Cats chase dogs", "n": 5}))?;
+    let ids = query::search_index(&db, cat())?;
+    assert_eq!(vec!["post2".to_string()], ids.results);
+    let ids = query::search_index(
+        &db,
+        vec![
+            query::QP::Match {
+                p: keypath!["body"],
+                q: "cat".to_string(),
+            },
+            query::QP::E {
+                p: keypath!["n"],
+                v: tv(5),
+            },
+        ],
+    )?;
+    assert_eq!(vec!["post2".to_string()], ids.results);
+
+    // A TextIndex with no paths removes them
+    let none = docdb::WriteOptions {
+        text: Some(text::TextIndex::new(vec![])),
+    };
+    docdb::set_document_with_options(&db, "post3", json!({"body": "A dog"}), &none)?;
+    let ids = query::search_index(
+        &db,
+        vec![query::QP::Match {
+            p: keypath!["body"],
+            q: "dog".to_string(),
+        }],
+    )?;
+    assert_eq!(vec!["post2".to_string()], ids.results);
+
+    // Deleting a document removes its terms
+    docdb::delete_document(&db, "post2")?;
+    let ids = query::search_index(&db, cat())?;
+    assert_eq!(0, ids.results.len());
+
+    // Match isn't supported within ElemMatch
+    let r = query::search_index(
+        &db,
+        vec![query::QP::ElemMatch {
+            p: keypath!["body"],
+            qps: cat(),
+        }],
+    );
+    assert!(matches!(r, Err(DocDbError::InvalidQuery(_))));
+
+    Ok(())
+}
+
+#[test]
+fn query_selector() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let q =
+        selector::parse_selector(&json!({"name": "john", "age": {"$gt": 20}, "a.c": {"$lte": 2}}))?;
+    let ids = query::search_index(&db, q)?;
+    assert_eq!(vec!["doc2".to_string(), "doc3".to_string()], ids.results);
+
+    // $ne matches documents without the field, as in MongoDB
+    let q = selector::parse_selector(&json!({"a.b": {"$ne": 1}}))?;
+    let ids = query::search_index(&db, q)?;
+    assert_eq!(vec!["doc2".to_string(), "doc3".to_string()], ids.results);
+
+    let q =
+        selector::parse_selector(&json!({"$or": [{"pet.$[]": "dog"}, {"age": {"$gte": 100}}]}))?;
+    let ids = query::search_index(&db, q)?;
+    assert_eq!(vec!["doc1".to_string(), "doc3".to_string()], ids.results);
+
+    // Selector errors describe what is wrong and where
+    let e = selector::parse_selector(&json!({"age": {"$gt": {"n": 1}}})).unwrap_err();
+    assert_eq!("age.$gt", e.at);
+
+    // The empty selector is rejected, rather than matching nothing
+    let r = selector::parse_selector(&json!({})).map_err(DocDbError::from);
+    assert!(matches!(r, Err(DocDbError::InvalidQuery(_))));
+
+    Ok(())
+}
+
+#[test]
+fn query_parse() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+
+    let q = query::parse(r#"name = "john" AND age > 20"#)?;
+    let ids = query::search_index(&db, q)?;
+    assert_eq!(vec!["doc2".to_string(), "doc3".to_string()], ids.results);
+
+    let q = query::parse(r#"pet[*] = "dog" OR (a.c = 2 AND pet MISSING)"#)?;
+    let ids = query::search_index(&db, q)?;
+    assert_eq!(vec!["doc1".to_string(), "doc2".to_string()], ids.results);
+
+    let e = query::parse("age >> 20").unwrap_err();
+    assert_eq!(5..6, e.span);
+
+    Ok(())
+}
+
+#[test]
+fn query_order_by() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(&db, "doc4", json!({"a": {"c": 3}}))?;
+    docdb::set_document(&db, "doc5", json!({"a": {"b": 5}, "age": "old"}))?;
+    let order_by = |p: Vec<TaggableValue>, direction: query::Direction| query::QueryOptions {
+        order_by: Some(query::OrderBy { p, direction }),
+        ..Default::default()
+    };
+    let exists_a = || vec![query::QP::Exists { p: keypath!["a"] }];
+
+    // Documents without the field come last in either direction
+    let ids = query::search_index_with_options(
+        &db,
+        exists_a(),
+        &order_by(keypath!["age"], query::Direction::Asc),
+    )?;
+    assert_eq!(vec!["doc2", "doc1", "doc3", "doc5", "doc4"], ids.results);
+    assert_eq!(2, ids.stats.scans);
+    let ids = query::search_index_with_options(
+        &db,
+        exists_a(),
+        &order_by(keypath!["age"], query::Direction::Desc),
+    )?;
+    assert_eq!(vec!["doc5", "doc3", "doc1", "doc2", "doc4"], ids.results);
+
+    // A query on just the sort field is answered by one scan in order
+    let ids = query::search_index_with_options(
+        &db,
+        vec![query::QP::GT {
+            p: keypath!["age"],
+            v: tv(30),
+        }],
+        &order_by(keypath!["age"], query::Direction::Asc),
+    )?;
+    assert_eq!(vec!["doc1", "doc3", "doc5"], ids.results);
+    assert_eq!(1, ids.stats.scans);
+    let ids = query::search_index_with_options(
+        &db,
+        vec![
+            query::QP::LT {
+                p: keypath!["age"],
+                v: tv(100),
+            },
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(20),
+            },
+        ],
+        &order_by(keypath!["age"], query::Direction::Desc),
+    )?;
+    assert_eq!(vec!["doc1", "doc2"], ids.results);
+    assert_eq!(1, ids.stats.scans);
+
+    // Other predicates are evaluated first, then sorted
+    let ids = query::search_index_with_options(
+        &db,
+        vec![
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(20),
+            },
+        ],
+        &order_by(keypath!["age"], query::Direction::Desc),
+    )?;
+    assert_eq!(vec!["doc3", "doc2"], ids.results);
+    assert_eq!(3, ids.stats.scans);
+
+    // Arrays sort by their lowest or highest element
+    let pets = keypath!["pet", TaggableValue::AnyIndex];
+    let ids = query::search_index_with_options(
+        &db,
+        exists_a(),
+        &order_by(pets.clone(), query::Direction::Asc),
+    )?;
+    assert_eq!(vec!["doc1", "doc3", "doc2", "doc4", "doc5"], ids.results);
+    let ids =
+        query::search_index_with_options(&db, exists_a(), &order_by(pets, query::Direction::Desc))?;
+    assert_eq!(vec!["doc3", "doc1", "doc2", "doc4", "doc5"], ids.results);
+
+    Ok(())
+}
+
+// Fetches every page of q, calling between with the page number
+// after each page, and returns the results of each page.
+fn all_pages(
+    db: &Db,
+    q: impl Fn() -> query::Query,
+    mut opts: query::QueryOptions,
+    mut between: impl FnMut(usize) -> Result<(), DocDbError>,
+) -> Result<Vec<Vec<String>>, DocDbError> {
+    let mut pages = vec![];
+    loop {
+        let r = query::search_index_with_options(db, q(), &opts)?;
+        pages.push(r.results);
+        between(pages.len())?;
+        match r.next {
+            Some(next) => opts.after = Some(next),
+            None => return Ok(pages),
+        }
+    }
+}
+
+#[test]
+fn query_limit_skip() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..10 {
+        docdb::set_document(&db, &format!("doc{}", i), json!({"n": i}))?;
+    }
+    let q = || vec![query::QP::Exists { p: keypath!["n"] }];
+
+    let r = query::search_index_with_options(
+        &db,
+        q(),
+        &query::QueryOptions {
+            limit: Some(3),
+            skip: 2,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(vec!["doc2", "doc3", "doc4"], r.results);
+    assert!(r.next.is_some());
+
+    // No next page once the results run out
+    let r = query::search_index_with_options(
+        &db,
+        q(),
+        &query::QueryOptions {
+            limit: Some(3),
+            skip: 7,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(vec!["doc7", "doc8", "doc9"], r.results);
+    assert!(r.next.is_none());
+
+    let r = query::search_index_with_options(
+        &db,
+        q(),
+        &query::QueryOptions {
+            after: Some("not a token".to_string()),
+            ..Default::default()
+        },
+    );
+    assert!(matches!(r, Err(DocDbError::InvalidQuery(_))));
+
+    Ok(())
+}
+
+#[test]
+fn query_pagination_concurrent_inserts() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..10 {
+        docdb::set_document(&db, &format!("doc{}", i), json!({"n": i, "kind": "a"}))?;
+    }
+    docdb::set_document(&db, "doc10", json!({"kind": "a"}))?;
+    docdb::set_document(&db, "doc11", json!({"kind": "a"}))?;
+
+    // In ID order, documents inserted before the current page
+    // aren't returned and those after are.
+    let pages = all_pages(
+        &db,
+        || vec![query::QP::Exists { p: keypath!["n"] }],
+        query::QueryOptions {
+            limit: Some(3),
+            ..Default::default()
+        },
+        |page| match page {
+            1 => {
+                docdb::set_document(&db, "doc05", json!({"n": 5}))?;
+                docdb::set_document(&db, "doc00", json!({"n": 0}))?;
+                // After the page, and outside the ranges queried below
+                docdb::set_document(&db, "doc75", json!({"n": -1}))
+            }
+            _ => Ok(()),
+        },
+    )?;
+    assert_eq!(
+        vec![
+            vec!["doc0", "doc1", "doc2"],
+            vec!["doc3", "doc4", "doc5"],
+            vec!["doc6", "doc7", "doc75"],
+            vec!["doc8", "doc9"],
+        ],
+        pages
+    );
+
+    // Sorted by a predicate field, each page resumes its scan from
+    // the last index key.
+    let pages = all_pages(
+        &db,
+        || {
+            vec![query::QP::GTE {
+                p: keypath!["n"],
+                v: tv(3),
+            }]
+        },
+        query::QueryOptions {
+            limit: Some(2),
+            order_by: Some(query::OrderBy {
+                p: keypath!["n"],
+                direction: query::Direction::Desc,
+            }),
+            ..Default::default()
+        },
+        |page| match page {
+            1 => {
+                docdb::set_document(&db, "doc20", json!({"n": 100}))?;
+                docdb::set_document(&db, "doc21", json!({"n": 6.5}))
+            }
+            _ => Ok(()),
+        },
+    )?;
+    assert_eq!(
+        vec![
+            vec!["doc9", "doc8"],
+            vec!["doc7", "doc21"],
+            vec!["doc6", "doc5"],
+            vec!["doc05", "doc4"],
+            vec!["doc3"],
+        ],
+        pages
+    );
+
+    // Documents without the sort field are paged through last
+    let pages = all_pages(
+        &db,
+        || {
+            vec![query::QP::E {
+                p: keypath!["kind"],
+                v: tv("a"),
+            }]
+        },
+        query::QueryOptions {
+            limit: Some(4),
+            order_by: Some(query::OrderBy {
+                p: keypath!["n"],
+                direction: query::Direction::Asc,
+            }),
+            ..Default::default()
+        },
+        |page| match page {
+            1 => docdb::set_document(&db, "doc02", json!({"kind": "a", "n": 1.5})),
+            2 => docdb::set_document(&db, "doc12", json!({"kind": "a"})),
+            _ => Ok(()),
+        },
+    )?;
+    assert_eq!(
+        vec![
+            vec!["doc0", "doc1", "doc2", "doc3"],
+            vec!["doc4", "doc5", "doc6", "doc7"],
+            vec!["doc8", "doc9", "doc10", "doc11"],
+            vec!["doc12"],
+        ],
+        pages
+    );
+
+    Ok(())
+}
+
+#[test]
+fn query_documents() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    let john = || {
+        vec![query::QP::E {
+            p: keypath!["name"],
+            v: tv("john"),
+        }]
+    };
+
+    let r = query::search_documents(&db, john())?;
+    assert_eq!(
+        vec![
+            (
+                "doc2".to_string(),
+                json!({"a":{"c": 2}, "name": "john", "age": 24})
+            ),
+            (
+                "doc3".to_string(),
+                json!({"a":{"c": 2}, "name": "john", "age": 110, "pet": ["wombat"]})
+            ),
+        ],
+        r.results
+    );
+
+    // Projections return only the given fields, and work with paging
+    let opts = query::QueryOptions {
+        fields: Some(vec![keypath!["age"], keypath!["pet", 0]]),
+        limit: Some(1),
+        ..Default::default()
+    };
+    let r = query::search_documents_with_options(&db, john(), &opts)?;
+    assert_eq!(vec![("doc2".to_string(), json!({"age": 24}))], r.results);
+    let opts = query::QueryOptions {
+        after: r.next,
+        ..opts
+    };
+    let r = query::search_documents_with_options(&db, john(), &opts)?;
+    assert_eq!(
+        vec![("doc3".to_string(), json!({"age": 110, "pet": ["wombat"]}))],
+        r.results
+    );
+    assert!(r.next.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn query_iter() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    docdb::set_document(
+        &db,
+        "doc4",
+        json!({"name": "john", "age": 24, "a": {"c": 2}}),
+    )?;
+    let collect = |q: query::Query| -> Result<Vec<String>, DocDbError> {
+        query::search_index_iter(&db, q)?.collect()
+    };
+
+    // A single field is streamed in index order
+    let ids = collect(vec![
+        query::QP::GT {
+            p: keypath!["age"],
+            v: tv(20),
+        },
+        query::QP::LT {
+            p: keypath!["age"],
+            v: tv(100),
+        },
+    ])?;
+    assert_eq!(vec!["doc2", "doc4", "doc1"], ids);
+
+    // Equality on several fields is intersected
+    let ids = collect(vec![
+        query::QP::E {
+            p: keypath!["name"],
+            v: tv("john"),
+        },
+        query::QP::E {
+            p: keypath!["age"],
+            v: tv(24),
+        },
+        query::QP::E {
+            p: keypath!["a", "c"],
+            v: tv(2),
+        },
+    ])?;
+    assert_eq!(vec!["doc2", "doc4"], ids);
+    let ids = collect(vec![
+        query::QP::E {
+            p: keypath!["name"],
+            v: tv("john"),
+        },
+        query::QP::E {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            v: tv("wombat"),
+        },
+    ])?;
+    assert_eq!(vec!["doc3"], ids);
+    let ids = collect(vec![
+        query::QP::E {
+            p: keypath!["name"],
+            v: tv("john"),
+        },
+        query::QP::E {
+            p: keypath!["name"],
+            v: tv("mike"),
+        },
+    ])?;
+    assert_eq!(0, ids.len());
+
+    // Like search_index, each scan only reads the values at exactly
+    // its path, even when another predicate is on a field below it
+    let tmp_dir2 = tempdir().unwrap();
+    let db2 = docdb::new_database(tmp_dir2.path()).unwrap();
+    docdb::set_document(&db2, "d1", json!({"a": {"b": 1}, "c": 1}))?;
+    docdb::set_document(&db2, "d2", json!({"a": "b", "c": 1}))?;
+    docdb::set_document(&db2, "d3", json!({"a": {"b": 1}, "c": 2}))?;
+    for q in [
+        "a = \"b\" AND a.b = 1",
+        "a = \"b\" AND c = 1",
+        "a.b = 1 AND c = 1",
+    ] {
+        let ids: Vec<String> =
+            query::search_index_iter(&db2, query::parse(q).unwrap())?.collect::<Result<_, _>>()?;
+        let r = query::search_index(&db2, query::parse(q).unwrap())?;
+        assert_eq!(r.results, ids);
+    }
+
+    // The iterator is lazy, so results can be taken a few at a time
+    let mut iter = query::search_index_iter(
+        &db,
+        vec![query::QP::GTE {
+            p: keypath!["age"],
+            v: tv(0),
+        }],
+    )?;
+    assert_eq!("doc2", iter.next().unwrap()?);
+    assert_eq!("doc4", iter.next().unwrap()?);
+
+    // Other queries need search_index
+    for q in [
+        vec![
+            query::QP::E {
+                p: keypath!["name"],
+                v: tv("john"),
+            },
+            query::QP::GT {
+                p: keypath!["age"],
+                v: tv(20),
+            },
+        ],
+        vec![query::QP::GT {
+            p: keypath!["pet", TaggableValue::AnyIndex],
+            v: tv("a"),
+        }],
+        vec![query::QP::Exists { p: keypath!["age"] }],
+        vec![query::QP::Or(vec![])],
+    ] {
+        let r = query::search_index_iter(&db, q);
+        assert!(matches!(r, Err(DocDbError::InvalidQuery(_))));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn query_explain() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    insert_test_data(&db)?;
+    let opts = query::QueryOptions::default();
+
+    // Equality first, then the ranges on age collapsed into one scan
+    let q = query::parse(r#"age > 30 AND name = "john" AND age < 200 AND pet SIZE >= 1"#).unwrap();
+    let e = query::explain(&db, q, &opts)?;
+    assert_eq!(
+        vec!["name = \"john\"", "age > 30", "age < 200", "pet SIZE >= 1"],
+        e.order
+    );
+    let steps: Vec<_> = e
+        .steps
+        .iter()
+        .map(|s| (s.description.as_str(), s.ids, s.keys()))
+        .collect();
+    assert_eq!(
+        vec![
+            ("name = \"john\"", Some(2), 2),
+            ("ranges of age", Some(1), 2),
+            ("pet SIZE >= 1", Some(1), 2),
+        ],
+        steps
+    );
+    let scan = &e.steps[1].scans[0];
+    assert_eq!(
+        ("after 30", "before 200"),
+        (scan.start.as_str(), scan.end.as_str())
+    );
+    let scan = &e.steps[2].scans[0];
+    assert!(scan.array_sizes);
+    assert_eq!(
+        ("before 1", "end"),
+        (scan.start.as_str(), scan.end.as_str())
+    );
+    assert!(!e.short_circuited);
+    assert_eq!(1, e.results);
+    assert_eq!(3, e.stats.scans);
+    assert_eq!(
+        "order: name = \"john\", age > 30, age < 200, pet SIZE >= 1\n\
+         1. name = \"john\": 2 ids\n   \
+            scan name from before \"john\" to after \"john\": 2 keys\n\
+         2. ranges of age: 1 ids\n   \
+            scan age from after 30 to before 200: 2 keys\n\
+         3. pet SIZE >= 1: 1 ids\n   \
+            scan pet SIZE from before 1 to end: 2 keys\n\
+         results: 1, scans: 3",
+        e.to_string()
+    );
+
+    // Nested predicates' scans are part of their step, and later
+    // steps aren't evaluated once there can be no results.
+    let q = query::parse(r#"name IN ["fred", "mike"] AND (age < 30 OR pet = "dog")"#).unwrap();
+    let e = query::explain(&db, q, &opts)?;
+    assert_eq!(2, e.steps[0].scans.len());
+    assert_eq!(Some(1), e.steps[0].ids);
+    assert_eq!(Some(0), e.steps[1].ids);
+    assert!(!e.short_circuited);
+
+    let q = query::parse(r#"name = "fred" AND age > 30"#).unwrap();
+    let e = query::explain(&db, q, &opts)?;
+    assert_eq!(Some(0), e.steps[0].ids);
+    assert_eq!(None, e.steps[1].ids);
+    assert!(e.steps[1].scans.is_empty());
+    assert!(e.short_circuited);
+    assert_eq!(1, e.stats.scans);
+
+    // Ranges that don't overlap don't need any scans
+    let q = query::parse("age > 30 AND age < 20").unwrap();
+    let e = query::explain(&db, q, &opts)?;
+    assert!(e.steps.is_empty());
+    assert!(e.short_circuited);
+    assert_eq!(0, e.stats.scans);
+
+    Ok(())
+}
+
+#[test]
+fn query_cost_ordering() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..20 {
+        let doc = json!({"email": format!("user{}@example.com", i), "active": i != 7});
+        docdb::set_document(&db, &format!("doc{}", i), doc)?;
+    }
+    let opts = query::QueryOptions::default();
+
+    // By variant alone, E would be evaluated before In
+    let q = query::parse(r#"active = true AND email IN ["user3@example.com"]"#).unwrap();
+    let e = query::explain(&db, q, &opts)?;
+    assert_eq!(
+        vec!["email IN [\"user3@example.com\"]", "active = true"],
+        e.order
+    );
+    assert_eq!(Some(1), e.steps[0].ids);
+    assert_eq!(1, e.results);
+
+    // The selective value of a field goes first
+    let q = query::parse(r#"active = true AND active != false AND active = false"#).unwrap();
+    let e = query::explain(&db, q, &opts)?;
+    assert_eq!("active = false", e.order[0]);
+    assert!(e.short_circuited);
+
+    // Estimates follow updates and deletes
+    let q = || query::parse(r#"active = false AND email PREFIX "user1""#).unwrap();
+    let e = query::explain(&db, q(), &opts)?;
+    assert_eq!("active = false", e.order[0]);
+    for i in 3..20 {
+        docdb::set_document(&db, &format!("doc{}", i), json!({"active": false}))?;
+    }
+    docdb::delete_document(&db, "doc0")?;
+    let e = query::explain(&db, q(), &opts)?;
+    assert_eq!("email PREFIX \"user1\"", e.order[0]);
+    assert_eq!(Some(1), e.steps[0].ids);
+    assert_eq!(0, e.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_eq_intersection() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..50 {
+        let doc = json!({
+            "group": i % 5,
+            "bucket": i % 7,
+            "pet": if i % 2 == 0 { json!(["cat", "dog"]) } else { json!(["cat"]) },
+        });
+        docdb::set_document(&db, &format!("doc{:02}", i), doc)?;
+    }
+    let opts = query::QueryOptions::default();
+
+    // The E predicates are one step, with a scan for each
+    let q =
+        || query::parse(r#"group = 3 AND bucket = 1 AND pet[*] = "dog" AND pet SIZE > 1"#).unwrap();
+    let e = query::explain(&db, q(), &opts)?;
+    assert_eq!(
+        "bucket = 1 AND group = 3 AND pet[*] = \"dog\"",
+        e.steps[0].description
+    );
+    assert_eq!(3, e.steps[0].scans.len());
+    // Fewer keys are read than are in the scans
+    assert!(e.steps[0].keys() < 7 + 10 + 25);
+    let ids = query::search_index(&db, q())?;
+    assert_eq!(vec!["doc08".to_string()], ids.results);
+
+    // Updated documents are found with their new values
+    docdb::set_document(
+        &db,
+        "doc08",
+        json!({"group": 3, "bucket": 2, "pet": ["dog"]}),
+    )?;
+    docdb::set_document(
+        &db,
+        "doc99",
+        json!({"group": 3, "bucket": 1, "pet": ["dog", "cat"]}),
+    )?;
+    docdb::delete_document(&db, "doc08")?;
+    let ids = query::search_index(&db, q())?;
+    assert_eq!(vec!["doc99".to_string()], ids.results);
+
+    // A field named like the value doesn't match
+    docdb::set_document(&db, "doc98", json!({"a": {"b": "c"}, "c": 1}))?;
+    docdb::set_document(&db, "doc97", json!({"a": "b", "c": 1}))?;
+    let ids = query::search_index(&db, query::parse(r#"a = "b" AND c = 1"#).unwrap())?;
+    assert_eq!(vec!["doc97".to_string()], ids.results);
+
+    // NOT with and without other predicates
+    let ids = query::search_index(&db, query::parse("NOT group = 3 AND bucket = 1").unwrap())?;
+    assert_eq!(5, ids.results.len());
+    let ids = query::search_index(&db, query::parse("NOT group EXISTS").unwrap())?;
+    assert_eq!(vec!["doc97".to_string(), "doc98".to_string()], ids.results);
+
+    Ok(())
+}
+
+#[test]
+fn query_parallel() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..200 {
+        let doc = json!({
+            "group": i % 5,
+            "bucket": i % 7,
+            "score": i % 50,
+            "tag": if i % 3 == 0 { "odd" } else { "even" },
+        });
+        docdb::set_document(&db, &format!("doc{:03}", i), doc)?;
+    }
+    let sequential = query::QueryOptions::default();
+    let parallel = query::QueryOptions {
+        parallelism: 4,
+        ..Default::default()
+    };
+
+    for q in [
+        "group = 3 AND bucket = 1",
+        "score > 10 AND score < 40 AND tag = \"odd\" AND group >= 2",
+        "group = 1 OR bucket = 2 OR score < 5",
+        "group IN [1, 2, 3] AND NOT bucket = 4",
+        "(group = 1 OR tag = \"odd\") AND (bucket = 2 OR score > 45)",
+    ] {
+        let want = query::search_index_with_options(&db, query::parse(q).unwrap(), &sequential)?;
+        let got = query::search_index_with_options(&db, query::parse(q).unwrap(), &parallel)?;
+        assert_eq!(want.results, got.results, "{}", q);
+        assert_eq!(want.stats.scans, got.stats.scans, "{}", q);
+    }
+
+    // Steps evaluated at once are all scanned before short-circuiting
+    let q = || query::parse("group = 9 AND bucket = 1 AND score > 1").unwrap();
+    let want = query::search_index_with_options(&db, q(), &sequential)?;
+    let got = query::search_index_with_options(&db, q(), &parallel)?;
+    assert!(got.results.is_empty());
+    assert_eq!(2, want.stats.scans);
+    assert_eq!(3, got.stats.scans);
+
+    // Steps in the same batch are explained as when run in turn
+    let q = || query::parse("score > 10 AND score < 40 AND tag = \"odd\" AND group = 2").unwrap();
+    let want = query::explain(&db, q(), &sequential)?;
+    let got = query::explain(&db, q(), &parallel)?;
+    assert_eq!(want.to_string(), got.to_string());
+
+    Ok(())
+}
+
+#[test]
+fn query_limits() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..100 {
+        let doc = json!({"age": i, "group": i % 5});
+        docdb::set_document(&db, &format!("doc{:02}", i), doc)?;
+    }
+    let q = || query::parse("age >= false").unwrap();
+    let search = |opts: query::QueryOptions| query::search_index_with_options(&db, q(), &opts);
+
+    match search(query::QueryOptions {
+        max_keys: Some(10),
+        ..Default::default()
+    }) {
+        Err(DocDbError::LimitExceeded(query::Limit::Keys, stats)) => assert_eq!(1, stats.scans),
+        _ => panic!("expected the keys limit to be exceeded"),
+    }
+    let r = search(query::QueryOptions {
+        max_keys: Some(100),
+        max_ids: Some(100),
+        ..Default::default()
+    })?;
+    assert_eq!(100, r.results.len());
+
+    match search(query::QueryOptions {
+        max_ids: Some(50),
+        ..Default::default()
+    }) {
+        Err(DocDbError::LimitExceeded(query::Limit::Ids, _)) => {}
+        _ => panic!("expected the IDs limit to be exceeded"),
+    }
+    // Only the IDs held by each step count
+    let opts = query::QueryOptions {
+        max_ids: Some(50),
+        ..Default::default()
+    };
+    let r = query::search_index_with_options(
+        &db,
+        query::parse("age < 50 AND group = 1").unwrap(),
+        &opts,
+    )?;
+    assert_eq!(10, r.results.len());
+
+    match search(query::QueryOptions {
+        deadline: Some(std::time::Instant::now()),
+        ..Default::default()
+    }) {
+        Err(DocDbError::LimitExceeded(query::Limit::Deadline, _)) => {}
+        _ => panic!("expected the deadline to pass"),
+    }
+
+    let cancel = query::CancelHandle::new();
+    let opts = query::QueryOptions {
+        cancel: Some(cancel.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        100,
+        query::search_index_with_options(&db, q(), &opts)?
+            .results
+            .len()
+    );
+    cancel.cancel();
+    match query::search_index_with_options(&db, q(), &opts) {
+        Err(DocDbError::LimitExceeded(query::Limit::Cancelled, _)) => {}
+        _ => panic!("expected the query to be cancelled"),
+    }
+
+    // Scans on other threads count towards the limits too
+    let opts = query::QueryOptions {
+        max_keys: Some(150),
+        parallelism: 2,
+        ..Default::default()
+    };
+    let q = query::parse("age >= 0 AND group >= 0").unwrap();
+    match query::search_index_with_options(&db, q, &opts) {
+        Err(DocDbError::LimitExceeded(query::Limit::Keys, stats)) => assert_eq!(2, stats.scans),
+        _ => panic!("expected the keys limit to be exceeded"),
+    }
+
+    // As do the full-text index's keys
+    insert_text_data(&db)?;
+    let q = || query::parse(r#"body MATCH "cat""#).unwrap();
+    assert_eq!(2, query::search_index(&db, q())?.results.len());
+    let opts = query::QueryOptions {
+        max_keys: Some(2),
+        ..Default::default()
+    };
+    match query::search_index_with_options(&db, q(), &opts) {
+        Err(DocDbError::LimitExceeded(query::Limit::Keys, stats)) => assert_eq!(1, stats.scans),
+        _ => panic!("expected the keys limit to be exceeded"),
+    }
+    let opts = query::QueryOptions {
+        cancel: Some(cancel),
+        ..Default::default()
+    };
+    match query::search_index_with_options(&db, q(), &opts) {
+        Err(DocDbError::LimitExceeded(query::Limit::Cancelled, _)) => {}
+        _ => panic!("expected the query to be cancelled"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn query_stats() -> Result<(), DocDbError> {
+    let tmp_dir = tempdir().unwrap();
+    let db = docdb::new_database(tmp_dir.path()).unwrap();
+    for i in 0..50 {
+        let doc = json!({"age": i, "group": i % 5});
+        docdb::set_document(&db, &format!("doc{:02}", i), doc)?;
+    }
+
+    let r = query::search_index(&db, query::parse("age >= 10 AND group = 1").unwrap())?;
+    assert_eq!(8, r.results.len());
+    assert_eq!(2, r.stats.scans);
+    assert_eq!(vec![10, 40], r.stats.scan_keys);
+    assert!(r.stats.bytes_read > 0);
+    assert_eq!(0, r.stats.decode_failures);
+    let steps: Vec<(&str, u64)> = r
+        .stats
+        .steps
+        .iter()
+        .map(|s| (s.description.as_str(), s.ids))
+        .collect();
+    assert_eq!(vec![("group = 1", 10), ("age >= 10", 8)], steps);
+    assert_eq!(0, r.stats.docs_fetched);
+
+    // search_documents counts the documents it reads
+    let opts = query::QueryOptions {
+        limit: Some(3),
+        ..Default::default()
+    };
+    let q = query::parse("group = 1").unwrap();
+    let r = query::search_documents_with_options(&db, q, &opts)?;
+    assert_eq!(3, r.results.len());
+    assert_eq!(3, r.stats.docs_fetched);
+
+    // The callback gets the stats of every query, including those
+    // stopped by a limit.
+    let logged = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let log = logged.clone();
+    let opts = query::QueryOptions {
+        max_keys: Some(20),
+        on_stats: Some(Box::new(move |q: &str, stats: &query::QueryStats| {
+            log.lock()
+                .unwrap()
+                .push((q.to_string(), stats.scan_keys.clone(), stats.docs_fetched));
+        })),
+        ..Default::default()
+    };
+    query::search_index_with_options(&db, query::parse("group = 1").unwrap(), &opts)?;
+    query::search_documents_with_options(&db, query::parse("group = 4").unwrap(), &opts)?;
+    match query::search_index_with_options(&db, query::parse("age >= 0").unwrap(), &opts) {
+        Err(DocDbError::LimitExceeded(query::Limit::Keys, stats)) => {
+            assert_eq!(vec![21], stats.scan_keys)
+        }
+        _ => panic!("expected the keys limit to be exceeded"),
+    }
+    assert_eq!(
+        vec![
+            ("group = 1".to_string(), vec![10], 0),
+            ("group = 4".to_string(), vec![10], 10),
+            ("age >= 0".to_string(), vec![21], 0)
+        ],
+        *logged.lock().unwrap()
+    );
+
+    Ok(())
+}